@@ -6,10 +6,11 @@ use rcgen::{
     ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, RcgenError, SanType,
 };
 use rustls::{
-    server::{ClientHello, ResolvesServerCert},
+    server::{ClientCertVerified, ClientCertVerifier, ClientHello, ResolvesServerCert},
     sign::CertifiedKey,
+    DistinguishedNames,
 };
-use std::sync::Arc;
+use std::{sync::Arc, time::SystemTime};
 use time::{ext::NumericalDuration, OffsetDateTime};
 use tokio_rustls::rustls::{self, ServerConfig};
 
@@ -135,12 +136,16 @@ impl CertificateAuthority {
         self.ca_cert_string.clone()
     }
 
-    pub fn gen_server_config(self: Arc<Self>) -> Arc<ServerConfig> {
-        let server_cfg = ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_cert_resolver(self);
-        Arc::new(server_cfg)
+    /// With `request_client_cert` clients are asked for a certificate, which
+    /// is optional and accepted without verification.
+    pub fn gen_server_config(self: Arc<Self>, request_client_cert: bool) -> Arc<ServerConfig> {
+        let server_cfg = ServerConfig::builder().with_safe_defaults();
+        let server_cfg = if request_client_cert {
+            server_cfg.with_client_cert_verifier(Arc::new(AcceptAnyClientCert))
+        } else {
+            server_cfg.with_no_client_auth()
+        };
+        Arc::new(server_cfg.with_cert_resolver(self))
     }
 }
 
@@ -151,3 +156,26 @@ impl ResolvesServerCert for CertificateAuthority {
             .map(|name| self.get_certified_key(name))
     }
 }
+
+/// Asks for a client certificate without requiring one, and accepts any that
+/// is presented so it can be inspected by the handler.
+struct AcceptAnyClientCert;
+
+impl ClientCertVerifier for AcceptAnyClientCert {
+    fn client_auth_mandatory(&self) -> Option<bool> {
+        Some(false)
+    }
+
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        Some(vec![])
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
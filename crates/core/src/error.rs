@@ -3,6 +3,8 @@ use std::io;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
+// variant names are public api
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("invalid CA")]
     Tls(#[from] RcgenError),
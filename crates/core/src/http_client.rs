@@ -1,16 +1,17 @@
 use crate::error::Error;
 use hyper::{client::HttpConnector, Client};
 use hyper_proxy::{Proxy as UpstreamProxy, ProxyConnector};
-use rustls::client::{ServerCertVerified, ServerCertVerifier};
-use std::time::SystemTime;
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "request-native-tls")] {
         use hyper_tls::{HttpsConnector, native_tls::TlsConnector};
     } else {
         use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
-        use rustls::ClientConfig;
-        use std::sync::Arc;
+        use rustls::{
+            client::{ServerCertVerified, ServerCertVerifier},
+            ClientConfig,
+        };
+        use std::{sync::Arc, time::SystemTime};
     }
 }
 
@@ -55,12 +56,12 @@ pub fn gen_client(upstream_proxy: Option<UpstreamProxy>) -> Result<HttpClient, E
 
     if let Some(proxy) = upstream_proxy {
         let connector = ProxyConnector::from_proxy(https, proxy)?;
-        return Ok(HttpClient::Proxy(
+        Ok(HttpClient::Proxy(
             Client::builder()
                 .http1_title_case_headers(true)
                 .http1_preserve_header_case(true)
                 .build(connector),
-        ));
+        ))
     } else {
         Ok(HttpClient::Https(
             Client::builder()
@@ -71,9 +72,11 @@ pub fn gen_client(upstream_proxy: Option<UpstreamProxy>) -> Result<HttpClient, E
     }
 }
 
+#[cfg(not(feature = "request-native-tls"))]
 #[derive(Default)]
 struct TrustAllCertVerifier;
 
+#[cfg(not(feature = "request-native-tls"))]
 impl ServerCertVerifier for TrustAllCertVerifier {
     fn verify_server_cert(
         &self,
@@ -35,6 +35,9 @@ where
 
     pub mitm_filters: Vec<String>,
     pub handler: H,
+    /// Ask clients for a TLS certificate, see [`mitm::TlsInfo`].
+    #[builder(default)]
+    pub request_client_cert: bool,
 
     #[builder(default)]
     _custom_contex_data: PhantomData<D>,
@@ -51,6 +54,7 @@ where
         let ca = Arc::new(self.ca);
         let http_handler = Arc::new(self.handler);
         let mitm_filter = Arc::new(MitmFilter::new(self.mitm_filters));
+        let request_client_cert = self.request_client_cert;
 
         let tcp_listener = TcpListener::bind(self.listen_addr).await?;
         loop {
@@ -66,6 +70,7 @@ where
                         client: client.clone(),
                         http_handler: Arc::clone(&http_handler),
                         mitm_filter: Arc::clone(&mitm_filter),
+                        request_client_cert,
                         custom_contex_data: Default::default(),
                     };
 
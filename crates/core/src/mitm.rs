@@ -3,7 +3,7 @@ use crate::{
     handler::{CustomContextData, HttpHandler, MitmFilter},
     http_client::HttpClient,
 };
-use http::{header, uri::Scheme, HeaderValue, Uri};
+use http::{header, uri::Scheme, HeaderValue, StatusCode, Uri};
 use hyper::{
     body::HttpBody, server::conn::Http, service::service_fn, upgrade::Upgraded, Body, Method,
     Request, Response,
@@ -14,7 +14,8 @@ use tokio::{
     io::{AsyncRead, AsyncWrite},
     net::TcpStream,
 };
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::{rustls::ServerConnection, TlsAcceptor};
+use x509_parser::parse_x509_certificate;
 
 /// Enum representing either an HTTP request or response.
 #[derive(Debug)]
@@ -32,6 +33,41 @@ pub struct HttpContext<D: Default + Send + Sync> {
     pub custom_data: D,
 }
 
+/// Added to the extensions of every request received over TLS terminated by
+/// the proxy. Plain HTTP requests have none.
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfo {
+    /// The certificate the client presented, only ever set when the proxy
+    /// asks for one.
+    pub client_cert: Option<ClientCert>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    /// RFC 4514 distinguished names, e.g. `CN=client, O=Example`.
+    pub subject: String,
+    pub issuer: String,
+}
+
+impl TlsInfo {
+    fn of(conn: &ServerConnection) -> Self {
+        let client_cert = conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| match parse_x509_certificate(&cert.0) {
+                Ok((_, cert)) => Some(ClientCert {
+                    subject: cert.subject().to_string(),
+                    issuer: cert.issuer().to_string(),
+                }),
+                Err(err) => {
+                    debug!("client certificate not parsed: {}", err);
+                    None
+                }
+            });
+        Self { client_cert }
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct MitmProxy<H, D>
 where
@@ -43,6 +79,7 @@ where
 
     pub http_handler: Arc<H>,
     pub mitm_filter: Arc<MitmFilter<D>>,
+    pub request_client_cert: bool,
 
     pub custom_contex_data: PhantomData<D>,
 }
@@ -120,11 +157,31 @@ where
             header_mut.remove(http::header::CONTENT_LENGTH);
         }
 
-        let res = match self.client {
+        let client_upgrade = if is_upgrade_request(&req) {
+            Some(hyper::upgrade::on(&mut req))
+        } else {
+            None
+        };
+
+        let mut res = match self.client {
             HttpClient::Proxy(client) => client.request(req).await?,
             HttpClient::Https(client) => client.request(req).await?,
         };
 
+        if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+            if let Some(client_upgrade) = client_upgrade {
+                let server_upgrade = hyper::upgrade::on(&mut res);
+                tokio::task::spawn(async move {
+                    match (client_upgrade.await, server_upgrade.await) {
+                        (Ok(mut client), Ok(mut server)) => {
+                            _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+                        }
+                        (Err(e), _) | (_, Err(e)) => debug!("upgrade error: {}", e),
+                    }
+                });
+            }
+        }
+
         let mut res = self.http_handler.handle_response(&mut ctx, res).await;
         let length = res.size_hint().lower();
 
@@ -176,16 +233,20 @@ where
     }
 
     pub async fn serve_tls<IO: AsyncRead + AsyncWrite + Unpin + Send + 'static>(self, stream: IO) {
-        let server_config = self.ca.clone().gen_server_config();
+        let server_config = self.ca.clone().gen_server_config(self.request_client_cert);
 
         match TlsAcceptor::from(server_config).accept(stream).await {
             Ok(stream) => {
+                let tls_info = TlsInfo::of(stream.get_ref().1);
                 if let Err(e) = Http::new()
                     .http1_preserve_header_case(true)
                     .http1_title_case_headers(true)
                     .serve_connection(
                         stream,
-                        service_fn(|req| self.clone().process_request(req, Scheme::HTTPS)),
+                        service_fn(|mut req: Request<Body>| {
+                            req.extensions_mut().insert(tls_info.clone());
+                            self.clone().process_request(req, Scheme::HTTPS)
+                        }),
                     )
                     .with_upgrades()
                     .await
@@ -235,6 +296,14 @@ fn allow_all_cros(res: &mut Response<Body>) {
     header_mut.insert(http::header::ACCESS_CONTROL_ALLOW_METHODS, all);
 }
 
+fn is_upgrade_request(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(header::CONNECTION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_lowercase().contains("upgrade"))
+        .unwrap_or(false)
+}
+
 fn host_addr(uri: &http::Uri) -> Option<String> {
     uri.authority().map(|auth| auth.to_string())
 }
@@ -0,0 +1,134 @@
+use http::HeaderValue;
+use hyper::{header, HeaderMap};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+/// Rewrites the request `Accept` header to ask upstream for a particular
+/// representation.
+///
+/// The `prefer` media ranges are put first with full quality. The client's
+/// other ranges are kept with their quality capped at `0.5`, unless `only` is
+/// set, in which case they are dropped.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AcceptRewrite {
+    pub prefer: Vec<String>,
+    #[serde(default)]
+    pub only: bool,
+}
+
+const KEPT_QUALITY: f32 = 0.5;
+
+impl AcceptRewrite {
+    pub fn modify_headers(&self, headers: &mut HeaderMap) {
+        let mut ranges: Vec<String> = self.prefer.clone();
+        if !self.only {
+            for range in media_ranges(headers) {
+                if self.prefer.iter().any(|p| range.is(p)) {
+                    continue;
+                }
+                let q = range.q.min(KEPT_QUALITY);
+                ranges.push(format!("{};q={}", range.range, q));
+            }
+        }
+
+        match HeaderValue::from_str(&ranges.join(", ")) {
+            Ok(value) => {
+                headers.insert(header::ACCEPT, value);
+            }
+            Err(_) => error!("accept prefer invalid: {:?}", self.prefer),
+        }
+    }
+}
+
+/// A media range of an `Accept` header, split from its quality.
+pub(crate) struct MediaRange {
+    /// The range with its parameters other than `q`, e.g. `text/html;level=1`.
+    range: String,
+    kind: String,
+    subkind: String,
+    q: f32,
+}
+
+impl MediaRange {
+    fn parse(text: &str) -> Option<Self> {
+        let mut params = text.split(';').map(str::trim);
+        let media = params.next()?.to_ascii_lowercase();
+        let (kind, subkind) = media.split_once('/')?;
+        if kind.is_empty() || subkind.is_empty() {
+            return None;
+        }
+
+        let mut range = media.clone();
+        let mut q = 1.0;
+        for param in params {
+            match param.split_once('=') {
+                Some((name, value)) if name.trim().eq_ignore_ascii_case("q") => {
+                    q = value.trim().parse::<f32>().ok()?.clamp(0.0, 1.0);
+                }
+                _ if !param.is_empty() => {
+                    range.push(';');
+                    range.push_str(param);
+                }
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            kind: kind.to_owned(),
+            subkind: subkind.to_owned(),
+            range,
+            q,
+        })
+    }
+
+    /// How specific a match of `kind/subkind` is, `None` for no match.
+    fn specificity(&self, kind: &str, subkind: &str) -> Option<u8> {
+        match (self.kind.as_str(), self.subkind.as_str()) {
+            ("*", "*") => Some(0),
+            (k, "*") if k == kind => Some(1),
+            (k, s) if k == kind && s == subkind => Some(2),
+            _ => None,
+        }
+    }
+
+    fn is(&self, range: &str) -> bool {
+        self.range.eq_ignore_ascii_case(range.trim())
+    }
+}
+
+pub(crate) fn media_ranges(headers: &HeaderMap) -> Vec<MediaRange> {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(MediaRange::parse)
+        .collect()
+}
+
+/// Whether the client accepts `media` with a non-zero quality.
+pub(crate) fn accepts(headers: &HeaderMap, media: &str) -> bool {
+    quality(headers, media) > 0.0
+}
+
+/// The quality the client gives `media`. The most specific matching range
+/// decides, as RFC 7231 section 5.3.2 asks, and a missing `Accept` header
+/// accepts everything at full quality.
+pub(crate) fn quality(headers: &HeaderMap, media: &str) -> f32 {
+    if !headers.contains_key(header::ACCEPT) {
+        return 1.0;
+    }
+    let media = media.to_ascii_lowercase();
+    let (kind, subkind) = match media.split_once('/') {
+        Some(split) => split,
+        None => return 0.0,
+    };
+
+    media_ranges(headers)
+        .iter()
+        .filter_map(|range| Some((range.specificity(kind, subkind)?, range.q)))
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, q)| q)
+        .unwrap_or(0.0)
+}
@@ -0,0 +1,121 @@
+use fancy_regex::Captures;
+use http::Uri;
+use hyper::{body::to_bytes, Body, Request, Response, StatusCode};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::{collections::hash_map::RandomState, hash::BuildHasher, str::FromStr, sync::OnceLock};
+
+use super::modify::TextTypes;
+use crate::cache::get_regex;
+
+/// Hash keys are drawn once per process, so a given id maps to the same fake
+/// id for the whole run but differs between runs.
+static ID_HASHER: OnceLock<RandomState> = OnceLock::new();
+
+/// Replaces ids matched by `patterns` in the url and body with fake ones.
+///
+/// When a pattern has a capture group only the first group is replaced,
+/// otherwise the whole match is.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AnonymizeId {
+    pub patterns: Vec<String>,
+}
+
+impl AnonymizeId {
+    pub async fn anonymize_req(
+        &self,
+        req: Request<Body>,
+        types: &TextTypes,
+    ) -> Option<Request<Body>> {
+        let (mut parts, body) = req.into_parts();
+
+        let url = self.anonymize(&parts.uri.to_string());
+        match Uri::from_str(&url) {
+            Ok(uri) => parts.uri = uri,
+            Err(err) => error!("anonymize url error: {}", err),
+        }
+
+        if !types.matches(&parts.headers) {
+            return Some(Request::from_parts(parts, body));
+        }
+        match to_bytes(body).await {
+            Ok(content) => match String::from_utf8(content.to_vec()) {
+                Ok(text) => Some(Request::from_parts(
+                    parts,
+                    Body::from(self.anonymize(&text)),
+                )),
+                Err(_) => Some(Request::from_parts(parts, Body::from(content))),
+            },
+            // req body read failed
+            Err(_) => None,
+        }
+    }
+
+    pub async fn anonymize_res(&self, res: Response<Body>, types: &TextTypes) -> Response<Body> {
+        let (parts, body) = res.into_parts();
+        if !types.matches(&parts.headers) {
+            return Response::from_parts(parts, body);
+        }
+        match to_bytes(body).await {
+            Ok(content) => match String::from_utf8(content.to_vec()) {
+                Ok(text) => Response::from_parts(parts, Body::from(self.anonymize(&text))),
+                Err(_) => Response::from_parts(parts, Body::from(content)),
+            },
+            Err(err) => Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(Body::from(err.to_string()))
+                .unwrap(),
+        }
+    }
+
+    fn anonymize(&self, text: &str) -> String {
+        let mut text = text.to_owned();
+        for pattern in &self.patterns {
+            text = get_regex(pattern)
+                .replace_all(&text, |caps: &Captures| {
+                    let whole = caps.get(0).unwrap();
+                    match caps.get(1) {
+                        Some(id) => {
+                            let start = id.start() - whole.start();
+                            let end = id.end() - whole.start();
+                            let whole = whole.as_str();
+                            format!(
+                                "{}{}{}",
+                                &whole[..start],
+                                fake_id(id.as_str()),
+                                &whole[end..]
+                            )
+                        }
+                        None => fake_id(whole.as_str()),
+                    }
+                })
+                .to_string();
+        }
+        text
+    }
+}
+
+/// Derives a fake id with the same length as `id`, replacing every digit and
+/// keeping any other character, so the result still fits the original format.
+fn fake_id(id: &str) -> String {
+    let mut seed = ID_HASHER.get_or_init(RandomState::new).hash_one(id);
+
+    id.chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if !c.is_ascii_digit() {
+                return c;
+            }
+            // splitmix64 step, good enough to spread the hash over many digits
+            seed = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^= z >> 31;
+            // avoid a leading zero so the id still parses as the same number width
+            let digit = if i == 0 { 1 + z % 9 } else { z % 10 };
+            char::from(b'0' + digit as u8)
+        })
+        .collect()
+}
@@ -0,0 +1,92 @@
+use http::{HeaderValue, Uri};
+use serde::{Deserialize, Serialize};
+
+/// Forces a download by setting `Content-Disposition: attachment`.
+///
+/// `filename` may use `{host}` for the request host and `{name}` for the last
+/// segment of the request path.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Attachment {
+    #[serde(default = "default_filename")]
+    pub filename: String,
+}
+
+fn default_filename() -> String {
+    "{name}".to_owned()
+}
+
+impl Attachment {
+    pub fn content_disposition(&self, uri: &Uri) -> HeaderValue {
+        let name = uri
+            .path()
+            .rsplit('/')
+            .find(|s| !s.is_empty())
+            .unwrap_or("download");
+        let filename = self
+            .filename
+            .replace("{host}", uri.host().unwrap_or_default())
+            .replace("{name}", &percent_decode(name));
+
+        let mut value = format!("attachment; filename=\"{}\"", ascii_fallback(&filename));
+        // RFC 6266: `filename*` carries the exact name, `filename` is for
+        // clients that don't understand it
+        if !filename.bytes().all(|b| (0x20..0x7f).contains(&b)) || filename.contains('"') {
+            value.push_str(&format!("; filename*=UTF-8''{}", rfc5987_encode(&filename)));
+        }
+        // every byte is visible ascii by construction
+        HeaderValue::from_str(&value).unwrap()
+    }
+}
+
+/// A quoted-string safe stand-in: non-ascii and control characters become
+/// `_`, quotes and backslashes are escaped.
+fn ascii_fallback(filename: &str) -> String {
+    let mut fallback = String::with_capacity(filename.len());
+    for c in filename.chars() {
+        match c {
+            '"' | '\\' => {
+                fallback.push('\\');
+                fallback.push(c);
+            }
+            c if (' '..='~').contains(&c) => fallback.push(c),
+            _ => fallback.push('_'),
+        }
+    }
+    fallback
+}
+
+/// Percent-encodes everything but RFC 5987 `attr-char`.
+fn rfc5987_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for b in text.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+/// Path segments are percent-encoded in the uri, names should not be.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Some(b) = text
+                .get(i + 1..i + 3)
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
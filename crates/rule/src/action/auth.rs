@@ -0,0 +1,326 @@
+use http::HeaderValue;
+use hyper::{header, Body, Request, Response, StatusCode};
+use log::{error, info};
+use moka::sync::Cache;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+static NONCE_HASHER: OnceLock<RandomState> = OnceLock::new();
+static NONCE_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Answers requests without acceptable credentials with a synthetic `401`
+/// carrying a `WWW-Authenticate` challenge, and lets the retried request with
+/// credentials through to upstream.
+///
+/// Outstanding challenges are kept per host and path in memory, so a retry
+/// can be told apart from credentials the client sent unasked. A digest retry
+/// must answer a nonce issued here, otherwise it is challenged again with
+/// `stale=true`. A challenge is dropped once answered, or when it wasn't
+/// within [`CHALLENGE_TTL`], and at most [`MAX_CHALLENGES`] are kept.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuthChallenge {
+    pub scheme: AuthScheme,
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    /// `user:password` for basic, the token for bearer and the username for
+    /// digest. Any credentials are accepted when unset.
+    #[serde(default)]
+    pub credentials: Option<String>,
+    /// Remove `Authorization` before forwarding the accepted request.
+    #[serde(default)]
+    pub strip: bool,
+
+    #[serde(skip)]
+    challenges: Arc<OnceLock<Cache<String, Option<String>>>>,
+}
+
+const MAX_CHALLENGES: u64 = 10_000;
+const CHALLENGE_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthScheme {
+    Basic,
+    Bearer,
+    Digest,
+}
+
+fn default_realm() -> String {
+    "good-mitm".to_owned()
+}
+
+/// Why credentials were refused, changing the challenge sent back.
+enum Refusal {
+    Missing,
+    Invalid,
+    Stale,
+}
+
+impl AuthChallenge {
+    /// Returns the challenge response, or `None` when the request may pass.
+    pub fn check(&self, req: &mut Request<Body>) -> Option<Response<Body>> {
+        let key = format!(
+            "{}{}",
+            req.uri().host().unwrap_or_default(),
+            req.uri().path()
+        );
+        let challenges = self.challenges.get_or_init(|| {
+            Cache::builder()
+                .max_capacity(MAX_CHALLENGES)
+                .time_to_live(CHALLENGE_TTL)
+                .build()
+        });
+
+        let authorization = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_owned());
+        let refusal = match authorization {
+            Some(authorization) => self.verify(&authorization, challenges.get(&key).as_ref()),
+            None => Err(Refusal::Missing),
+        };
+
+        match refusal {
+            Ok(()) => {
+                match challenges.get(&key) {
+                    Some(_) => {
+                        challenges.invalidate(&key);
+                        info!("[AuthChallenge] {} retried after challenge", key)
+                    }
+                    None => info!("[AuthChallenge] {} sent credentials unasked", key),
+                }
+                if self.strip {
+                    req.headers_mut().remove(header::AUTHORIZATION);
+                }
+                None
+            }
+            Err(refusal) => {
+                let nonce = match self.scheme {
+                    AuthScheme::Digest => Some(generate_nonce()),
+                    _ => None,
+                };
+                let res = self.challenge(&refusal, nonce.as_deref());
+                challenges.insert(key, nonce);
+                Some(res)
+            }
+        }
+    }
+
+    /// `issued` is the outstanding challenge for the request, if any.
+    fn verify(&self, authorization: &str, issued: Option<&Option<String>>) -> Result<(), Refusal> {
+        let (scheme, credentials) = authorization.split_once(' ').unwrap_or((authorization, ""));
+        let credentials = credentials.trim();
+        let expected = self.credentials.as_deref();
+
+        match self.scheme {
+            AuthScheme::Basic if scheme.eq_ignore_ascii_case("basic") => {
+                let decoded = base64::decode(credentials)
+                    .ok()
+                    .and_then(|v| String::from_utf8(v).ok())
+                    .ok_or(Refusal::Invalid)?;
+                match expected {
+                    Some(expected) if expected != decoded => Err(Refusal::Invalid),
+                    _ => Ok(()),
+                }
+            }
+            AuthScheme::Bearer if scheme.eq_ignore_ascii_case("bearer") => match expected {
+                _ if credentials.is_empty() => Err(Refusal::Invalid),
+                Some(expected) if expected != credentials => Err(Refusal::Invalid),
+                _ => Ok(()),
+            },
+            AuthScheme::Digest if scheme.eq_ignore_ascii_case("digest") => {
+                let params = parse_params(credentials);
+                if let Some(expected) = expected {
+                    if params.get("username").map(String::as_str) != Some(expected) {
+                        return Err(Refusal::Invalid);
+                    }
+                }
+                match (params.get("nonce"), issued) {
+                    (Some(nonce), Some(Some(issued))) if nonce == issued => Ok(()),
+                    _ => Err(Refusal::Stale),
+                }
+            }
+            _ => Err(Refusal::Missing),
+        }
+    }
+
+    fn challenge(&self, refusal: &Refusal, nonce: Option<&str>) -> Response<Body> {
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        let challenge = match self.scheme {
+            AuthScheme::Basic => format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm),
+            AuthScheme::Bearer => match refusal {
+                Refusal::Missing => format!("Bearer realm=\"{}\"", realm),
+                _ => format!("Bearer realm=\"{}\", error=\"invalid_token\"", realm),
+            },
+            AuthScheme::Digest => {
+                let mut challenge = format!(
+                    "Digest realm=\"{}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"",
+                    realm,
+                    nonce.unwrap_or_default()
+                );
+                if let Refusal::Stale = refusal {
+                    challenge.push_str(", stale=true");
+                }
+                challenge
+            }
+        };
+
+        let mut res = Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(header::CONTENT_LENGTH, 0)
+            .body(Body::default())
+            .unwrap();
+        match HeaderValue::from_str(&challenge) {
+            Ok(value) => {
+                res.headers_mut().insert(header::WWW_AUTHENTICATE, value);
+            }
+            Err(err) => error!("auth challenge {} invalid: {}", challenge, err),
+        }
+        res
+    }
+}
+
+/// The `key=value` pairs of a digest `Authorization`, quotes removed.
+fn parse_params(credentials: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = credentials;
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_lowercase();
+        let value = value.trim_start();
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            },
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        params.insert(key, value.trim().to_owned());
+        rest = next;
+    }
+    params
+}
+
+/// A nonce unique within the process, unpredictable across runs.
+fn generate_nonce() -> String {
+    let hasher = NONCE_HASHER.get_or_init(RandomState::new);
+    let seq = NONCE_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!(
+        "{:016x}{:016x}",
+        hasher.hash_one((seq, now, 0u8)),
+        hasher.hash_one((seq, now, 1u8))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "http://example.com/private";
+
+    fn auth(scheme: &str, credentials: Option<&str>) -> AuthChallenge {
+        serde_json::from_value(serde_json::json!({
+            "scheme": scheme,
+            "credentials": credentials,
+        }))
+        .unwrap()
+    }
+
+    fn request(authorization: Option<&str>) -> Request<Body> {
+        let mut req = Request::builder().uri(URL);
+        if let Some(authorization) = authorization {
+            req = req.header(header::AUTHORIZATION, authorization);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn challenged(auth: &AuthChallenge) -> bool {
+        let challenges = auth.challenges.get().unwrap();
+        challenges.contains_key("example.com/private")
+    }
+
+    fn nonce(res: &Response<Body>) -> String {
+        let challenge = res.headers()[header::WWW_AUTHENTICATE].to_str().unwrap();
+        parse_params(challenge.strip_prefix("Digest ").unwrap())["nonce"].clone()
+    }
+
+    #[test]
+    fn basic() {
+        let auth = auth("basic", Some("user:secret"));
+        let res = auth.check(&mut request(None)).unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            res.headers()[header::WWW_AUTHENTICATE],
+            "Basic realm=\"good-mitm\", charset=\"UTF-8\""
+        );
+        assert!(challenged(&auth));
+
+        let wrong = format!("Basic {}", base64::encode("user:wrong"));
+        assert!(auth.check(&mut request(Some(&wrong))).is_some());
+
+        // answering the challenge drops it
+        let right = format!("Basic {}", base64::encode("user:secret"));
+        let mut req = request(Some(&right));
+        assert!(auth.check(&mut req).is_none());
+        assert!(req.headers().contains_key(header::AUTHORIZATION));
+        assert!(!challenged(&auth));
+    }
+
+    #[test]
+    fn digest_nonce() {
+        let auth = auth("digest", Some("user"));
+        let res = auth.check(&mut request(None)).unwrap();
+        let issued = nonce(&res);
+        assert!(!res.headers()[header::WWW_AUTHENTICATE]
+            .to_str()
+            .unwrap()
+            .contains("stale"));
+
+        // a nonce not issued here is stale, and replaces the challenge
+        let made_up = "Digest username=\"user\", nonce=\"0\", response=\"x\"";
+        let res = auth.check(&mut request(Some(made_up))).unwrap();
+        assert!(res.headers()[header::WWW_AUTHENTICATE]
+            .to_str()
+            .unwrap()
+            .ends_with(", stale=true"));
+        let reissued = nonce(&res);
+        assert_ne!(issued, reissued);
+
+        let stale = format!("Digest username=\"user\", nonce=\"{}\"", issued);
+        let res = auth.check(&mut request(Some(&stale))).unwrap();
+
+        let answer = format!("Digest username=\"user\", nonce=\"{}\"", nonce(&res));
+        assert!(auth.check(&mut request(Some(&answer))).is_none());
+
+        // the nonce is only good once
+        assert!(auth.check(&mut request(Some(&answer))).is_some());
+    }
+
+    #[test]
+    fn bearer_strip() {
+        let mut auth = auth("bearer", None);
+        auth.strip = true;
+        let res = auth.check(&mut request(Some("Bearer "))).unwrap();
+        assert_eq!(
+            res.headers()[header::WWW_AUTHENTICATE],
+            "Bearer realm=\"good-mitm\", error=\"invalid_token\""
+        );
+
+        let mut req = request(Some("Bearer any"));
+        assert!(auth.check(&mut req).is_none());
+        assert!(!req.headers().contains_key(header::AUTHORIZATION));
+    }
+}
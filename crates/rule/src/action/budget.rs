@@ -0,0 +1,297 @@
+use futures_util::{stream, StreamExt};
+use http::{header::HeaderName, HeaderValue, Uri};
+use hyper::{
+    body::{Bytes, HttpBody},
+    header, Body, HeaderMap, Response, StatusCode,
+};
+use log::{error, warn};
+use moka::sync::Cache;
+use serde::{Deserialize, Serialize};
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use super::encoding::{self, DecodedSize};
+
+/// Warns when a response body is larger than `budget` bytes, keeping per
+/// host and path statistics in memory for as long as the process runs, for
+/// at most [`MAX_PATHS`] of them.
+///
+/// The size is that of the decoded body. It is counted as the body streams
+/// through, passed on as it came, and recorded when the body ends or, if it
+/// is cut short, once it has gone over budget.
+///
+/// The response is left untouched unless `header` is set, in which case the
+/// header is added to responses over budget. Unless an unencoded body
+/// declares its `Content-Length`, that means holding the body back until it
+/// ends or goes over budget, so at most about `budget` bytes are buffered.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BodyBudget {
+    pub budget: u64,
+    #[serde(default)]
+    pub header: Option<String>,
+
+    #[serde(skip)]
+    stats: Arc<OnceLock<Cache<String, Arc<Mutex<BudgetStats>>>>>,
+}
+
+const MAX_PATHS: u64 = 10_000;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct BudgetStats {
+    responses: u64,
+    violations: u64,
+    max_size: u64,
+}
+
+/// Where the size of one response is recorded.
+struct Tally {
+    stats: Cache<String, Arc<Mutex<BudgetStats>>>,
+    key: String,
+    budget: u64,
+}
+
+impl Tally {
+    fn record(&self, size: u64) {
+        let stats = self.stats.get_with(self.key.clone(), Default::default);
+        let stats = {
+            let mut stats = stats.lock().unwrap();
+            stats.responses += 1;
+            stats.max_size = stats.max_size.max(size);
+            if size > self.budget {
+                stats.violations += 1;
+            }
+            *stats
+        };
+
+        if size > self.budget {
+            warn!(
+                "[BodyBudget] {} {} bytes over budget {} (violations {}/{}, max {})",
+                self.key, size, self.budget, stats.violations, stats.responses, stats.max_size
+            );
+        }
+    }
+}
+
+/// A body being counted, recorded when it is dropped.
+struct Metered {
+    body: Body,
+    size: DecodedSize,
+    tally: Tally,
+    done: bool,
+}
+
+impl Metered {
+    fn over(&self) -> bool {
+        self.size.size() > self.tally.budget
+    }
+
+    async fn next(&mut self) -> Option<hyper::Result<Bytes>> {
+        match self.body.data().await {
+            Some(Ok(chunk)) => {
+                self.size.write(&chunk);
+                Some(Ok(chunk))
+            }
+            Some(Err(err)) => Some(Err(err)),
+            None => {
+                self.size.finish();
+                self.done = true;
+                None
+            }
+        }
+    }
+
+    fn into_body(self, read: Vec<Bytes>) -> Body {
+        let read = stream::iter(read.into_iter().map(Ok));
+        let rest = stream::unfold(self, |mut metered| async move {
+            let chunk = metered.next().await?;
+            Some((chunk, metered))
+        });
+        Body::wrap_stream(read.chain(rest))
+    }
+}
+
+impl Drop for Metered {
+    fn drop(&mut self) {
+        // a body cut short under budget says nothing about its size
+        if self.done || self.over() {
+            self.tally.record(self.size.size());
+        }
+    }
+}
+
+impl BodyBudget {
+    pub async fn check(&self, url: &str, res: Response<Body>) -> Response<Body> {
+        let (mut parts, body) = res.into_parts();
+
+        let key = match Uri::from_str(url) {
+            Ok(uri) => format!("{}{}", uri.host().unwrap_or_default(), uri.path()),
+            Err(_) => url.to_owned(),
+        };
+        let tally = Tally {
+            stats: self.stats.get_or_init(|| Cache::new(MAX_PATHS)).clone(),
+            key,
+            budget: self.budget,
+        };
+
+        // no need to count when the size is already known
+        let content_length = parts
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .filter(|_| !encoding::is_encoded(&parts.headers));
+        if let Some(size) = content_length {
+            tally.record(size);
+            if size > self.budget {
+                self.add_header(&mut parts.headers, &size.to_string());
+            }
+            return Response::from_parts(parts, body);
+        }
+
+        let mut metered = Metered {
+            body,
+            size: DecodedSize::new(&parts.headers),
+            tally,
+            done: false,
+        };
+        if self.header.is_none() {
+            return Response::from_parts(parts, metered.into_body(vec![]));
+        }
+
+        // held back until the size is known to be over budget or not
+        let mut read = vec![];
+        while !metered.over() {
+            match metered.next().await {
+                Some(Ok(chunk)) => read.push(chunk),
+                Some(Err(err)) => {
+                    return Response::builder()
+                        .status(StatusCode::BAD_GATEWAY)
+                        .body(Body::from(err.to_string()))
+                        .unwrap()
+                }
+                None => break,
+            }
+        }
+        if metered.over() {
+            let size = match metered.done {
+                true => metered.size.size().to_string(),
+                false => format!(">{}", self.budget),
+            };
+            self.add_header(&mut parts.headers, &size);
+        }
+        Response::from_parts(parts, metered.into_body(read))
+    }
+
+    fn add_header(&self, headers: &mut HeaderMap, size: &str) {
+        if let Some(ref name) = self.header {
+            match HeaderName::from_str(name) {
+                Ok(name) => {
+                    headers.insert(
+                        name,
+                        HeaderValue::from_str(&format!("{}; budget={}", size, self.budget))
+                            .unwrap(),
+                    );
+                }
+                Err(err) => error!("budget header {} invalid: {}", name, err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use hyper::body::to_bytes;
+    use std::{
+        io::Write,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    const URL: &str = "http://example.com/page";
+
+    fn budget(budget: u64, header: Option<&str>) -> BodyBudget {
+        BodyBudget {
+            budget,
+            header: header.map(str::to_owned),
+            stats: Default::default(),
+        }
+    }
+
+    fn stats(budget: &BodyBudget) -> BudgetStats {
+        let stats = budget.stats.get().unwrap().get("example.com/page").unwrap();
+        let stats = *stats.lock().unwrap();
+        stats
+    }
+
+    /// A body of `chunks` chunks of 1 KiB, counting the chunks read from it.
+    fn chunked(chunks: usize, read: Arc<AtomicUsize>) -> Body {
+        let chunks = stream::iter(0..chunks).map(move |_| {
+            read.fetch_add(1, Ordering::SeqCst);
+            Ok::<_, hyper::Error>(Bytes::from(vec![b'a'; 1 << 10]))
+        });
+        Body::wrap_stream(chunks)
+    }
+
+    #[tokio::test]
+    async fn streamed_without_header() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let res = Response::new(chunked(100, read.clone()));
+
+        let budget = budget(10 << 10, None);
+        let res = budget.check(URL, res).await;
+        assert_eq!(read.load(Ordering::SeqCst), 0);
+        assert_eq!(to_bytes(res.into_body()).await.unwrap().len(), 100 << 10);
+        let stats = stats(&budget);
+        assert_eq!((stats.responses, stats.violations), (1, 1));
+        assert_eq!(stats.max_size, 100 << 10);
+    }
+
+    #[tokio::test]
+    async fn held_back_until_over() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let res = Response::new(chunked(100, read.clone()));
+
+        let budget = budget(10 << 10, Some("x-body-budget"));
+        let res = budget.check(URL, res).await;
+        assert_eq!(read.load(Ordering::SeqCst), 11);
+        assert_eq!(res.headers()["x-body-budget"], ">10240; budget=10240");
+        assert_eq!(to_bytes(res.into_body()).await.unwrap().len(), 100 << 10);
+        assert_eq!(stats(&budget).max_size, 100 << 10);
+    }
+
+    #[tokio::test]
+    async fn under_budget() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let res = Response::new(chunked(4, read));
+
+        let budget = budget(10 << 10, Some("x-body-budget"));
+        let res = budget.check(URL, res).await;
+        assert!(!res.headers().contains_key("x-body-budget"));
+        assert_eq!(to_bytes(res.into_body()).await.unwrap().len(), 4 << 10);
+        let stats = stats(&budget);
+        assert_eq!((stats.responses, stats.violations), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn decoded_size() {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&vec![b'a'; 100 << 10]).unwrap();
+        let gzipped = Bytes::from(encoder.finish().unwrap());
+        assert!(gzipped.len() < 10 << 10);
+        let res = Response::builder()
+            .header(header::CONTENT_ENCODING, "gzip")
+            .header(header::CONTENT_LENGTH, gzipped.len())
+            .body(Body::from(gzipped.clone()))
+            .unwrap();
+
+        let budget = budget(10 << 10, None);
+        let res = budget.check(URL, res).await;
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), gzipped);
+        let stats = stats(&budget);
+        assert_eq!((stats.violations, stats.max_size), (1, 100 << 10));
+    }
+}
@@ -0,0 +1,257 @@
+use hyper::{header, HeaderMap};
+use quick_xml::{
+    escape::escape,
+    events::{BytesStart, Event},
+    Reader,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Converts a body between JSON and XML.
+///
+/// JSON to XML: the body becomes the `root` element, object keys become child
+/// elements, keys starting with `@` become attributes and `#text` the text of
+/// the element. Array items repeat the element of their key, a top-level
+/// array is a list of `item` elements. Scalars are written as text and `null`
+/// as an empty element. Characters not allowed in element names become `_`.
+///
+/// XML to JSON does the reverse: the root element is dropped, attributes
+/// become `@` keys, repeated child elements become arrays, an element with
+/// only text becomes a string and an empty one `null`. All values come out as
+/// strings since XML carries no types.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConvertBody {
+    pub to: BodyFormat,
+    #[serde(default = "default_root")]
+    pub root: String,
+    #[serde(default = "default_item")]
+    pub item: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BodyFormat {
+    Json,
+    Xml,
+}
+
+fn default_root() -> String {
+    "root".to_owned()
+}
+
+fn default_item() -> String {
+    "item".to_owned()
+}
+
+const ATTR_PREFIX: char = '@';
+const TEXT_KEY: &str = "#text";
+
+impl ConvertBody {
+    /// Whether the body is in the format converted from.
+    pub fn is_source(&self, headers: &HeaderMap) -> bool {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_lowercase();
+        match self.to {
+            BodyFormat::Json => content_type.contains("xml"),
+            BodyFormat::Xml => content_type.contains("json"),
+        }
+    }
+
+    /// The `Content-Type` of the converted body.
+    pub fn content_type(&self) -> &'static str {
+        match self.to {
+            BodyFormat::Json => "application/json",
+            BodyFormat::Xml => "application/xml; charset=utf-8",
+        }
+    }
+
+    /// Returns the converted body, or `None` when it can't be parsed.
+    pub fn exec_action(&self, content: &[u8]) -> Option<String> {
+        match self.to {
+            BodyFormat::Xml => {
+                let value: Value = serde_json::from_slice(content).ok()?;
+                let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+                match value {
+                    Value::Array(items) => {
+                        let root = element_name(&self.root);
+                        xml.push_str(&format!("<{}>", root));
+                        for item in &items {
+                            self.write_element(&mut xml, &self.item, item);
+                        }
+                        xml.push_str(&format!("</{}>", root));
+                    }
+                    value => self.write_element(&mut xml, &self.root, &value),
+                }
+                Some(xml)
+            }
+            BodyFormat::Json => {
+                let text = std::str::from_utf8(content).ok()?;
+                serde_json::to_string(&xml_to_json(text)?).ok()
+            }
+        }
+    }
+
+    fn write_element(&self, xml: &mut String, name: &str, value: &Value) {
+        let name = element_name(name);
+        match value {
+            Value::Array(items) => {
+                for item in items {
+                    self.write_element(xml, &name, item);
+                }
+            }
+            Value::Object(map) => {
+                xml.push('<');
+                xml.push_str(&name);
+                for (key, value) in map {
+                    if let Some(attr) = key.strip_prefix(ATTR_PREFIX) {
+                        xml.push_str(&format!(
+                            " {}=\"{}\"",
+                            element_name(attr),
+                            escape(&scalar_text(value))
+                        ));
+                    }
+                }
+                xml.push('>');
+                for (key, value) in map {
+                    if key == TEXT_KEY {
+                        xml.push_str(&escape(&scalar_text(value)));
+                    } else if !key.starts_with(ATTR_PREFIX) {
+                        self.write_element(xml, key, value);
+                    }
+                }
+                xml.push_str(&format!("</{}>", name));
+            }
+            Value::Null => xml.push_str(&format!("<{}/>", name)),
+            value => xml.push_str(&format!(
+                "<{}>{}</{}>",
+                name,
+                escape(&scalar_text(value)),
+                name
+            )),
+        }
+    }
+}
+
+fn scalar_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+/// A valid XML element name for `name`.
+fn element_name(name: &str) -> String {
+    let mut element: String = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '_' | '-' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !element.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+        element.insert(0, '_');
+    }
+    element
+}
+
+/// An element being read: its attributes and children, and its text.
+struct Node {
+    name: String,
+    map: Map<String, Value>,
+    text: String,
+}
+
+impl Node {
+    fn open(start: &BytesStart) -> Option<Self> {
+        let mut node = Node {
+            name: String::from_utf8_lossy(start.name().as_ref()).to_string(),
+            map: Map::new(),
+            text: String::new(),
+        };
+        for attr in start.attributes() {
+            let attr = attr.ok()?;
+            let key = String::from_utf8_lossy(attr.key.as_ref());
+            let value = attr.unescape_value().ok()?;
+            node.map.insert(
+                format!("{}{}", ATTR_PREFIX, key),
+                Value::String(value.to_string()),
+            );
+        }
+        Some(node)
+    }
+
+    fn into_value(mut self) -> Value {
+        if self.map.is_empty() {
+            return match self.text.is_empty() {
+                true => Value::Null,
+                false => Value::String(self.text),
+            };
+        }
+        if !self.text.is_empty() {
+            self.map
+                .insert(TEXT_KEY.to_owned(), Value::String(self.text));
+        }
+        Value::Object(self.map)
+    }
+
+    fn add_child(&mut self, name: String, value: Value) {
+        match self.map.get_mut(&name) {
+            Some(Value::Array(items)) => items.push(value),
+            Some(existing) => {
+                let first = existing.take();
+                *existing = Value::Array(vec![first, value]);
+            }
+            None => {
+                self.map.insert(name, value);
+            }
+        }
+    }
+}
+
+fn xml_to_json(text: &str) -> Option<Value> {
+    let mut reader = Reader::from_str(text);
+    reader.trim_text(true);
+    let mut stack: Vec<Node> = vec![];
+
+    loop {
+        let closed = match reader.read_event().ok()? {
+            Event::Start(start) => {
+                stack.push(Node::open(&start)?);
+                None
+            }
+            Event::Empty(empty) => Some(Node::open(&empty)?),
+            Event::End(_) => Some(stack.pop()?),
+            Event::Text(text) => {
+                stack.last_mut()?.text.push_str(&text.unescape().ok()?);
+                None
+            }
+            Event::CData(data) => {
+                stack
+                    .last_mut()?
+                    .text
+                    .push_str(&String::from_utf8_lossy(&data));
+                None
+            }
+            Event::Eof => return None,
+            _ => None,
+        };
+
+        if let Some(node) = closed {
+            match stack.last_mut() {
+                Some(parent) => {
+                    let name = node.name.clone();
+                    parent.add_child(name, node.into_value());
+                }
+                None => return Some(node.into_value()),
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use http::{header::HeaderName, HeaderValue};
+use hyper::{Body, Request, Response};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::SystemTime,
+};
+
+static ID_HASHER: OnceLock<RandomState> = OnceLock::new();
+static ID_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Makes sure the request carries a correlation id in `header`, generating
+/// one when the client didn't send it, and echoes the id on the response.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CorrelationId {
+    #[serde(default = "default_header")]
+    pub header: String,
+}
+
+fn default_header() -> String {
+    "x-request-id".to_owned()
+}
+
+impl CorrelationId {
+    /// Returns the id the request carries after this action, for the response.
+    pub fn modify_req(&self, req: &mut Request<Body>) -> Option<String> {
+        let name = match HeaderName::from_str(&self.header) {
+            Ok(name) => name,
+            Err(err) => {
+                error!("correlation id header {} invalid: {}", self.header, err);
+                return None;
+            }
+        };
+
+        if let Some(id) = req.headers().get(&name).and_then(|v| v.to_str().ok()) {
+            if !id.is_empty() {
+                return Some(id.to_owned());
+            }
+        }
+        let id = generate_id();
+        req.headers_mut()
+            .insert(name, HeaderValue::from_str(&id).unwrap());
+        Some(id)
+    }
+
+    pub fn modify_res(&self, id: &str, res: &mut Response<Body>) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_str(&self.header),
+            HeaderValue::from_str(id),
+        ) {
+            res.headers_mut().insert(name, value);
+        }
+    }
+}
+
+/// A random version 4 uuid, unique within the process thanks to the sequence
+/// number mixed into the hash.
+fn generate_id() -> String {
+    let hasher = ID_HASHER.get_or_init(RandomState::new);
+    let seq = ID_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let high = hasher.hash_one((seq, now, 0u8));
+    let low = hasher.hash_one((seq, now, 1u8));
+
+    let id = ((high as u128) << 64 | low as u128) & !(0xf000 << 64) & !(0xc << 60);
+    let id = id | (0x4000 << 64) | (0x8 << 60);
+    let hex = format!("{:032x}", id);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
@@ -0,0 +1,127 @@
+use cookie::Cookie;
+use hyper::{header, Body, Request};
+use moka::sync::Cache;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+
+use super::Action;
+
+/// Picks actions by how many times the rule has matched.
+///
+/// Counts start at 1, live in memory only and are reset on restart. They are
+/// shared by every clone of the rule and counted atomically, so concurrent
+/// requests each observe a distinct count.
+///
+/// At most [`MAX_SESSIONS`] sessions are kept, each dropped once it hasn't
+/// matched for `session-ttl` seconds, and counts again from 1 if it returns.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Counter {
+    /// Count separately for each value of this header or cookie.
+    #[serde(default)]
+    pub session: Option<SessionKey>,
+    /// Restart from 1 after this many matches.
+    #[serde(default)]
+    pub cycle: Option<u64>,
+    #[serde(default)]
+    pub on: Vec<CounterCase>,
+    #[serde(default)]
+    pub default: Vec<Action>,
+    #[serde(default = "default_session_ttl")]
+    pub session_ttl: u64,
+
+    #[serde(skip)]
+    counts: Arc<OnceLock<Cache<String, Arc<AtomicU64>>>>,
+}
+
+const MAX_SESSIONS: u64 = 10_000;
+
+fn default_session_ttl() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SessionKey {
+    Header(String),
+    Cookie(String),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CounterCase {
+    pub nth: Vec<u64>,
+    pub actions: Vec<Action>,
+}
+
+impl Counter {
+    /// Counts this match and returns the actions selected for it.
+    fn select(&self, req: &Request<Body>) -> Vec<Action> {
+        let session = self
+            .session
+            .as_ref()
+            .and_then(|key| key.value_of(req))
+            .unwrap_or_default();
+
+        let counts = self.counts.get_or_init(|| {
+            Cache::builder()
+                .max_capacity(MAX_SESSIONS)
+                .time_to_idle(Duration::from_secs(self.session_ttl))
+                .build()
+        });
+        let count = counts
+            .get_with(session, || Arc::new(AtomicU64::new(0)))
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        let count = match self.cycle {
+            Some(cycle) if cycle > 0 => (count - 1) % cycle + 1,
+            _ => count,
+        };
+
+        self.on
+            .iter()
+            .find(|case| case.nth.contains(&count))
+            .map(|case| &case.actions)
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+impl SessionKey {
+    fn value_of(&self, req: &Request<Body>) -> Option<String> {
+        match self {
+            SessionKey::Header(name) => req
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from),
+            SessionKey::Cookie(name) => req
+                .headers()
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split("; "))
+                .filter_map(|c| Cookie::parse(c).ok())
+                .find(|c| c.name() == name)
+                .map(|c| c.value().to_owned()),
+        }
+    }
+}
+
+/// Replaces every counter in `actions` with the actions it selects for this
+/// request, recursively.
+pub fn expand_counters(actions: &[Action], req: &Request<Body>) -> Vec<Action> {
+    actions
+        .iter()
+        .flat_map(|action| match action {
+            Action::Counter(counter) => expand_counters(&counter.select(req), req),
+            action => vec![action.clone()],
+        })
+        .collect()
+}
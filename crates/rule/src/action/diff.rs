@@ -0,0 +1,286 @@
+use cookie::Cookie;
+use hyper::{
+    body::{to_bytes, Bytes},
+    header, Body, HeaderMap, Request, Response,
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+use super::modify::within_limit;
+
+/// Logs a compact summary of what a modify changed instead of the whole
+/// payload: header and cookie names, the body size delta and a short snippet
+/// around each changed body region.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LogDiff {
+    /// How many bytes of each side of a body change to show.
+    #[serde(default = "default_snippet")]
+    pub snippet: usize,
+    /// Bodies larger than this many bytes are not captured for the diff, so
+    /// they keep streaming.
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+fn default_snippet() -> usize {
+    32
+}
+
+fn default_limit() -> usize {
+    1024 * 1024
+}
+
+/// At most this many body changes are reported per modify.
+const MAX_BODY_CHANGES: usize = 8;
+/// How far ahead to look for the two bodies to line up again after a change.
+const RESYNC_WINDOW: usize = 512;
+/// How many equal bytes count as the bodies lining up again.
+const RESYNC_LEN: usize = 8;
+
+/// The parts of a request or response a modify may change.
+pub(crate) struct Snapshot {
+    uri: Option<String>,
+    headers: HeaderMap,
+    body: Captured,
+}
+
+enum Captured {
+    /// The modify doesn't change the body.
+    Skipped,
+    /// The body is over the limit and was left streaming.
+    TooLarge,
+    Body(Bytes),
+}
+
+impl Snapshot {
+    /// Takes a snapshot, buffering the body only when `with_body` is set and
+    /// it has at most `limit` bytes.
+    pub async fn of_req(
+        req: Request<Body>,
+        with_body: bool,
+        limit: usize,
+    ) -> hyper::Result<(Request<Body>, Self)> {
+        let (parts, body) = req.into_parts();
+        let (body, bytes) = capture(&parts.headers, body, with_body, limit).await?;
+        let snapshot = Self {
+            uri: Some(parts.uri.to_string()),
+            headers: parts.headers.clone(),
+            body: bytes,
+        };
+        Ok((Request::from_parts(parts, body), snapshot))
+    }
+
+    pub async fn of_res(
+        res: Response<Body>,
+        with_body: bool,
+        limit: usize,
+    ) -> hyper::Result<(Response<Body>, Self)> {
+        let (parts, body) = res.into_parts();
+        let (body, bytes) = capture(&parts.headers, body, with_body, limit).await?;
+        let snapshot = Self {
+            uri: None,
+            headers: parts.headers.clone(),
+            body: bytes,
+        };
+        Ok((Response::from_parts(parts, body), snapshot))
+    }
+}
+
+async fn capture(
+    headers: &HeaderMap,
+    body: Body,
+    with_body: bool,
+    limit: usize,
+) -> hyper::Result<(Body, Captured)> {
+    if !with_body {
+        return Ok((body, Captured::Skipped));
+    }
+    let (body, fits) = within_limit(headers, body, limit).await?;
+    if !fits {
+        return Ok((body, Captured::TooLarge));
+    }
+    // already buffered by `within_limit`
+    let bytes = to_bytes(body).await?;
+    Ok((Body::from(bytes.clone()), Captured::Body(bytes)))
+}
+
+impl LogDiff {
+    pub(crate) fn log(&self, before: &Snapshot, after: &Snapshot) {
+        let mut summary = vec![];
+
+        if before.uri != after.uri {
+            summary.push("url changed".to_owned());
+        }
+
+        let headers = header_changes(&before.headers, &after.headers);
+        if !headers.is_empty() {
+            summary.push(format!("headers: {}", headers.join(" ")));
+        }
+
+        let cookies = cookie_changes(&before.headers, &after.headers);
+        if !cookies.is_empty() {
+            summary.push(format!("cookies: {}", cookies.join(" ")));
+        }
+
+        let mut body_changes = vec![];
+        match (&before.body, &after.body) {
+            (Captured::Body(old), Captured::Body(new)) => {
+                if old != new {
+                    summary.push(format!(
+                        "body: {} -> {} ({:+})",
+                        old.len(),
+                        new.len(),
+                        new.len() as i64 - old.len() as i64
+                    ));
+                    body_changes = body_changes_of(old, new);
+                }
+            }
+            (Captured::Skipped, Captured::Skipped) => {}
+            _ => summary.push("body not captured".to_owned()),
+        }
+
+        if summary.is_empty() {
+            return;
+        }
+        info!("[Diff] {}", summary.join("; "));
+        for change in body_changes {
+            info!(
+                "[Diff] body @{}: {:?} -> {:?}",
+                change.offset,
+                self.snippet_of(change.old),
+                self.snippet_of(change.new)
+            );
+        }
+    }
+
+    fn snippet_of(&self, bytes: &[u8]) -> String {
+        if bytes.len() <= self.snippet {
+            String::from_utf8_lossy(bytes).to_string()
+        } else {
+            format!("{}...", String::from_utf8_lossy(&bytes[..self.snippet]))
+        }
+    }
+}
+
+/// Names of changed headers, prefixed with `+` added, `-` removed or `~`
+/// changed. Cookies are summarized separately.
+fn header_changes(old: &HeaderMap, new: &HeaderMap) -> Vec<String> {
+    let names: BTreeSet<&str> = old
+        .keys()
+        .chain(new.keys())
+        .map(|k| k.as_str())
+        .filter(|k| *k != header::COOKIE.as_str() && *k != header::SET_COOKIE.as_str())
+        .collect();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old_values: Vec<_> = old.get_all(name).iter().collect();
+            let new_values: Vec<_> = new.get_all(name).iter().collect();
+            match (old_values.is_empty(), new_values.is_empty()) {
+                (true, false) => Some(format!("+{}", name)),
+                (false, true) => Some(format!("-{}", name)),
+                _ if old_values != new_values => Some(format!("~{}", name)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn cookie_changes(old: &HeaderMap, new: &HeaderMap) -> Vec<String> {
+    let old = cookies_of(old);
+    let new = cookies_of(new);
+    let names: BTreeSet<&str> = old
+        .iter()
+        .chain(new.iter())
+        .map(|(name, _)| name.as_str())
+        .collect();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old_value = old.iter().find(|(n, _)| n == name);
+            let new_value = new.iter().find(|(n, _)| n == name);
+            match (old_value, new_value) {
+                (None, Some(_)) => Some(format!("+{}", name)),
+                (Some(_), None) => Some(format!("-{}", name)),
+                (Some(a), Some(b)) if a != b => Some(format!("~{}", name)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn cookies_of(headers: &HeaderMap) -> Vec<(String, String)> {
+    let cookie = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split("; "));
+    let set_cookie = headers
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok());
+
+    cookie
+        .chain(set_cookie)
+        .filter_map(|c| Cookie::parse(c).ok())
+        .map(|c| (c.name().to_owned(), c.to_string()))
+        .collect()
+}
+
+struct BodyChange<'a> {
+    offset: usize,
+    old: &'a [u8],
+    new: &'a [u8],
+}
+
+/// Finds the changed regions between two bodies by skipping equal bytes and,
+/// on a mismatch, looking ahead for the closest point where they line up
+/// again. Good enough for the replace-style edits rules make.
+fn body_changes_of<'a>(old: &'a [u8], new: &'a [u8]) -> Vec<BodyChange<'a>> {
+    let mut changes = vec![];
+    let (mut i, mut j) = (0, 0);
+
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+            continue;
+        }
+        if changes.len() == MAX_BODY_CHANGES {
+            break;
+        }
+
+        let (skip_old, skip_new) = resync(&old[i..], &new[j..]);
+        changes.push(BodyChange {
+            offset: i,
+            old: &old[i..i + skip_old],
+            new: &new[j..j + skip_new],
+        });
+        i += skip_old;
+        j += skip_new;
+    }
+    changes
+}
+
+fn resync(old: &[u8], new: &[u8]) -> (usize, usize) {
+    let lines_up = |a: usize, b: usize| {
+        let len = RESYNC_LEN.min(old.len() - a).min(new.len() - b);
+        (len == RESYNC_LEN || (a + len == old.len() && b + len == new.len()))
+            && old[a..a + len] == new[b..b + len]
+    };
+
+    // try the smallest total skip first so the reported change stays tight
+    for total in 1..=RESYNC_WINDOW * 2 {
+        for a in total.saturating_sub(RESYNC_WINDOW)..=total.min(RESYNC_WINDOW) {
+            let b = total - a;
+            if a <= old.len() && b <= new.len() && lines_up(a, b) {
+                return (a, b);
+            }
+        }
+    }
+    (old.len(), new.len())
+}
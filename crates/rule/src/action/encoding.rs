@@ -0,0 +1,373 @@
+use encoding_rs::{Encoding, UTF_8};
+use flate2::{
+    read::{DeflateDecoder, GzDecoder, ZlibDecoder},
+    write,
+};
+use hyper::{body::Bytes, header, HeaderMap};
+use log::error;
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// The codings listed in `Content-Encoding`, in the order they were applied.
+fn codings(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::CONTENT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty() && v != "identity")
+        .collect()
+}
+
+/// Whether the body declared by these headers is compressed.
+pub(crate) fn is_encoded(headers: &HeaderMap) -> bool {
+    !codings(headers).is_empty()
+}
+
+/// How far a body is decompressed when the rule sets no `max-body`.
+pub(crate) const DECODED_LIMIT: usize = 64 << 20;
+
+/// Undoes the `Content-Encoding` of `content`, so rules can work on the plain
+/// body, and drops the header as the body is passed on unencoded. Callers
+/// are expected to update `Content-Length`.
+///
+/// `gzip`, `deflate` and `br` are supported, also stacked. With any other
+/// coding, a body that doesn't decode or one that decodes to over `limit`
+/// bytes, `content` is handed back in `Err` and the headers are left alone.
+pub(crate) fn decode(
+    headers: &mut HeaderMap,
+    content: Bytes,
+    limit: usize,
+) -> Result<Bytes, Bytes> {
+    let codings = codings(headers);
+    if codings.is_empty() {
+        return Ok(content);
+    }
+
+    let mut decoded = content.to_vec();
+    for coding in codings.iter().rev() {
+        decoded = match decode_one(coding, &decoded, limit) {
+            Some(decoded) => decoded,
+            None => return Err(content),
+        };
+    }
+
+    headers.remove(header::CONTENT_ENCODING);
+    Ok(decoded.into())
+}
+
+/// Reads at most one byte over `limit`, so a body that decompresses to far
+/// more than it weighs is caught early.
+fn decode_one(coding: &str, content: &[u8], limit: usize) -> Option<Vec<u8>> {
+    let take = limit as u64 + 1;
+    let mut decoded = vec![];
+    let result = match coding {
+        "gzip" | "x-gzip" => GzDecoder::new(content).take(take).read_to_end(&mut decoded),
+        // zlib wrapped as the spec says, but some servers send raw deflate
+        "deflate" => ZlibDecoder::new(content)
+            .take(take)
+            .read_to_end(&mut decoded)
+            .or_else(|_| {
+                decoded.clear();
+                DeflateDecoder::new(content)
+                    .take(take)
+                    .read_to_end(&mut decoded)
+            }),
+        "br" => brotli::Decompressor::new(content, 4096)
+            .take(take)
+            .read_to_end(&mut decoded),
+        _ => return None,
+    };
+    match result {
+        Ok(_) if decoded.len() > limit => {
+            error!("{} body decodes to over {} bytes", coding, limit);
+            None
+        }
+        Ok(_) => Some(decoded),
+        Err(err) => {
+            error!("{} body decode error: {}", coding, err);
+            None
+        }
+    }
+}
+
+/// Counts the decoded size of a body written to it chunk by chunk, without
+/// keeping it, for the codings [`decode`] supports. With any other coding,
+/// or once the body fails to decode, the bytes written are counted instead.
+pub(crate) struct DecodedSize {
+    decoder: Option<Box<dyn Write + Send>>,
+    decoded: Arc<AtomicU64>,
+    written: u64,
+}
+
+impl DecodedSize {
+    pub(crate) fn new(headers: &HeaderMap) -> Self {
+        let decoded = Arc::new(AtomicU64::new(0));
+        let mut decoder: Option<Box<dyn Write + Send>> = Some(Box::new(Count(decoded.clone())));
+        // the last coding applied is the first undone
+        for coding in codings(headers) {
+            decoder = decoder.and_then(|inner| decoder_of(&coding, inner));
+        }
+        Self {
+            decoder,
+            decoded,
+            written: 0,
+        }
+    }
+
+    pub(crate) fn write(&mut self, chunk: &[u8]) {
+        self.written += chunk.len() as u64;
+        if let Some(ref mut decoder) = self.decoder {
+            if let Err(err) = decoder.write_all(chunk) {
+                error!("body decode error: {}", err);
+                self.decoder = None;
+            }
+        }
+    }
+
+    /// Decodes what is left of the body after the last chunk.
+    pub(crate) fn finish(&mut self) {
+        if let Some(ref mut decoder) = self.decoder {
+            if let Err(err) = decoder.flush() {
+                error!("body decode error: {}", err);
+                self.decoder = None;
+            }
+        }
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        match self.decoder {
+            Some(_) => self.decoded.load(Ordering::Relaxed),
+            None => self.written,
+        }
+    }
+}
+
+fn decoder_of(coding: &str, inner: Box<dyn Write + Send>) -> Option<Box<dyn Write + Send>> {
+    match coding {
+        "gzip" | "x-gzip" => Some(Box::new(write::GzDecoder::new(inner))),
+        "deflate" => Some(Box::new(Inflate::Head(vec![], Some(inner)))),
+        "br" => Some(Box::new(brotli::DecompressorWriter::new(inner, 4096))),
+        _ => None,
+    }
+}
+
+struct Count(Arc<AtomicU64>);
+
+impl Write for Count {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.fetch_add(buf.len() as u64, Ordering::Relaxed);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Inflates zlib wrapped or raw deflate, told apart by the zlib header in
+/// the first two bytes.
+enum Inflate<W: Write> {
+    Head(Vec<u8>, Option<W>),
+    Zlib(write::ZlibDecoder<W>),
+    Raw(write::DeflateDecoder<W>),
+}
+
+impl<W: Write> Write for Inflate<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Inflate::Head(head, inner) => {
+                head.extend_from_slice(buf);
+                if head.len() < 2 {
+                    return Ok(buf.len());
+                }
+                let zlib =
+                    (u16::from(head[0]) << 8 | u16::from(head[1])) % 31 == 0 && head[0] & 0x0f == 8;
+                let head = std::mem::take(head);
+                let inner = inner.take().expect("inflate inner writer");
+                *self = match zlib {
+                    true => Inflate::Zlib(write::ZlibDecoder::new(inner)),
+                    false => Inflate::Raw(write::DeflateDecoder::new(inner)),
+                };
+                self.write_all(&head)?;
+                Ok(buf.len())
+            }
+            Inflate::Zlib(decoder) => decoder.write(buf),
+            Inflate::Raw(decoder) => decoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Inflate::Head(..) => Ok(()),
+            Inflate::Zlib(decoder) => decoder.flush(),
+            Inflate::Raw(decoder) => decoder.flush(),
+        }
+    }
+}
+
+/// The charset declared by `Content-Type`, if it is one we know.
+fn charset(headers: &HeaderMap) -> Option<&'static Encoding> {
+    let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    content_type
+        .split(';')
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .and_then(|(_, label)| Encoding::for_label(label.trim().trim_matches('"').as_bytes()))
+}
+
+/// Whether text bodies with these headers are UTF-8, the default when no
+/// charset is declared.
+pub(crate) fn is_utf8_text(headers: &HeaderMap) -> bool {
+    charset(headers).is_none_or(|charset| charset == UTF_8)
+}
+
+/// Decodes a text body in the charset of its `Content-Type`, or as UTF-8 when
+/// none is declared, returning the charset to encode it back with.
+///
+/// `None` when the body isn't valid in that charset, or the charset can't be
+/// written back, such as UTF-16.
+pub(crate) fn decode_text(
+    headers: &HeaderMap,
+    content: &[u8],
+) -> Option<(String, &'static Encoding)> {
+    let charset = charset(headers).unwrap_or(UTF_8);
+    if charset.output_encoding() != charset {
+        return None;
+    }
+    let text = charset.decode_without_bom_handling_and_without_replacement(content)?;
+    Some((text.into_owned(), charset))
+}
+
+/// Encodes text decoded by [`decode_text`] back to its charset. Characters
+/// the charset can't represent become HTML numeric character references.
+pub(crate) fn encode_text(text: &str, charset: &'static Encoding) -> Bytes {
+    let (bytes, _, _) = charset.encode(text);
+    Bytes::from(bytes.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{
+        write::{DeflateEncoder, GzEncoder, ZlibEncoder},
+        Compression,
+    };
+    use hyper::header::HeaderValue;
+
+    const HTML: &str = "<html><body>hello world</body></html>";
+
+    fn gzip(content: &[u8]) -> Bytes {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap().into()
+    }
+
+    fn brotli(content: &[u8]) -> Bytes {
+        let mut encoded = vec![];
+        let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+        encoder.write_all(content).unwrap();
+        drop(encoder);
+        encoded.into()
+    }
+
+    fn encoded_as(coding: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(coding));
+        headers
+    }
+
+    #[test]
+    fn decode_gzip() {
+        let mut headers = encoded_as("gzip");
+        let decoded = decode(&mut headers, gzip(HTML.as_bytes()), DECODED_LIMIT).unwrap();
+        assert_eq!(decoded, HTML.as_bytes());
+        assert!(!headers.contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn decode_brotli() {
+        let mut headers = encoded_as("br");
+        let decoded = decode(&mut headers, brotli(HTML.as_bytes()), DECODED_LIMIT).unwrap();
+        assert_eq!(decoded, HTML.as_bytes());
+        assert!(!headers.contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn decode_stacked() {
+        let mut headers = encoded_as("gzip, br");
+        let content = brotli(&gzip(HTML.as_bytes()));
+        assert_eq!(
+            decode(&mut headers, content, DECODED_LIMIT).unwrap(),
+            HTML.as_bytes()
+        );
+    }
+
+    #[test]
+    fn decode_unsupported() {
+        let mut headers = encoded_as("zstd");
+        let content = Bytes::from_static(b"\x28\xb5\x2f\xfd");
+        assert_eq!(
+            decode(&mut headers, content.clone(), DECODED_LIMIT),
+            Err(content)
+        );
+        assert!(headers.contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn decode_over_limit() {
+        let bomb = gzip(&vec![0; 1 << 20]);
+        let mut headers = encoded_as("gzip");
+        assert_eq!(
+            decode(&mut headers, bomb.clone(), 1 << 10),
+            Err(bomb.clone())
+        );
+        assert!(headers.contains_key(header::CONTENT_ENCODING));
+
+        let mut headers = encoded_as("gzip");
+        assert_eq!(decode(&mut headers, bomb, 1 << 20).unwrap().len(), 1 << 20);
+    }
+
+    /// The decoded size of `content` written in chunks of `chunk` bytes.
+    fn decoded_size(coding: &'static str, content: &[u8], chunk: usize) -> u64 {
+        let mut size = DecodedSize::new(&encoded_as(coding));
+        for chunk in content.chunks(chunk) {
+            size.write(chunk);
+        }
+        size.finish();
+        size.size()
+    }
+
+    #[test]
+    fn decoded_size_codings() {
+        let html = HTML.repeat(100);
+        let len = html.len() as u64;
+        let mut zlib = ZlibEncoder::new(vec![], Compression::default());
+        zlib.write_all(html.as_bytes()).unwrap();
+        let zlib = zlib.finish().unwrap();
+        let mut raw = DeflateEncoder::new(vec![], Compression::default());
+        raw.write_all(html.as_bytes()).unwrap();
+        let raw = raw.finish().unwrap();
+
+        for chunk in [1, 7, 4096] {
+            assert_eq!(decoded_size("gzip", &gzip(html.as_bytes()), chunk), len);
+            assert_eq!(decoded_size("br", &brotli(html.as_bytes()), chunk), len);
+            assert_eq!(decoded_size("deflate", &zlib, chunk), len);
+            assert_eq!(decoded_size("deflate", &raw, chunk), len);
+            let stacked = brotli(&gzip(html.as_bytes()));
+            assert_eq!(decoded_size("gzip, br", &stacked, chunk), len);
+        }
+    }
+
+    #[test]
+    fn decoded_size_unsupported() {
+        assert_eq!(decoded_size("zstd", b"\x28\xb5\x2f\xfd", 2), 4);
+        assert_eq!(decoded_size("gzip", b"not gzip at all", 2), 15);
+    }
+}
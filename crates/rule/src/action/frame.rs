@@ -0,0 +1,253 @@
+use hyper::{header, HeaderMap};
+use serde::{Deserialize, Serialize};
+
+use super::modify::TextModify;
+
+/// Applies a `TextModify` to every message of a length-prefixed body and
+/// rewrites the length prefixes to match.
+///
+/// Each frame is `flag-bytes` of flags, a `length-bytes` unsigned length and
+/// that many bytes of payload. The defaults are those of gRPC: a 1-byte flag
+/// and a 4-byte big-endian length. Only frames whose flag is listed in
+/// `flags` are modified, by default uncompressed gRPC messages, so trailer
+/// and compressed frames pass through untouched. The flag is ignored when
+/// `flag-bytes` is `0`.
+///
+/// Payloads that aren't UTF-8, or whose new length doesn't fit the prefix,
+/// are kept as they are. A frame that can't be parsed, such as one cut short,
+/// ends the parse and everything from it on is passed on unchanged.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FrameModify {
+    #[serde(default = "default_flag_bytes")]
+    pub flag_bytes: usize,
+    #[serde(default = "default_length_bytes")]
+    pub length_bytes: usize,
+    #[serde(default)]
+    pub little_endian: bool,
+    #[serde(default = "default_flags")]
+    pub flags: Vec<u64>,
+    /// Only bodies whose `Content-Type` contains one of these, ignoring case,
+    /// are parsed as frames, `application/grpc` by default, which also
+    /// covers gRPC-Web. Others pass through untouched. Base64 bodies of
+    /// `application/grpc-web-text` are decoded first and encoded again after.
+    #[serde(default = "default_content_types")]
+    pub content_types: Vec<String>,
+    pub body: TextModify,
+}
+
+fn default_content_types() -> Vec<String> {
+    vec!["application/grpc".to_owned()]
+}
+
+fn default_flag_bytes() -> usize {
+    1
+}
+
+fn default_length_bytes() -> usize {
+    4
+}
+
+fn default_flags() -> Vec<u64> {
+    vec![0]
+}
+
+impl FrameModify {
+    /// Whether the body declared by these headers is framed.
+    pub fn is_framed(&self, headers: &HeaderMap) -> bool {
+        let content_type = match headers.get(header::CONTENT_TYPE) {
+            Some(content_type) => content_type.to_str().unwrap_or_default().to_lowercase(),
+            None => return false,
+        };
+        self.content_types
+            .iter()
+            .any(|t| content_type.contains(&t.to_lowercase()))
+    }
+
+    /// Returns the reframed body, or `None` when no frame was changed or a
+    /// `application/grpc-web-text` body isn't valid base64.
+    pub fn exec_action(&self, headers: &HeaderMap, content: &[u8]) -> Option<Vec<u8>> {
+        if !is_text(headers) {
+            return self.reframe(content);
+        }
+        let decoded = decode_text(content)?;
+        self.reframe(&decoded)
+            .map(|framed| base64::encode(framed).into_bytes())
+    }
+
+    fn reframe(&self, content: &[u8]) -> Option<Vec<u8>> {
+        if self.flag_bytes > 8 || self.length_bytes == 0 || self.length_bytes > 8 {
+            return None;
+        }
+
+        let mut out = Vec::with_capacity(content.len());
+        let mut changed = false;
+        let mut rest = content;
+        while !rest.is_empty() {
+            let frame = match self.split_frame(rest) {
+                Some(frame) => frame,
+                None => {
+                    out.extend_from_slice(rest);
+                    break;
+                }
+            };
+            let (flag, payload, next) = frame;
+            rest = next;
+
+            let modified = match (self.is_modified(flag), std::str::from_utf8(payload)) {
+                (true, Ok(text)) => Some(self.body.exec_action(text)),
+                _ => None,
+            };
+            let payload = match modified {
+                Some(ref text) if self.fits(text.len()) => {
+                    changed |= text.as_bytes() != payload;
+                    text.as_bytes()
+                }
+                _ => payload,
+            };
+
+            out.extend_from_slice(&encode(flag, self.flag_bytes, self.little_endian));
+            out.extend_from_slice(&encode(
+                payload.len() as u64,
+                self.length_bytes,
+                self.little_endian,
+            ));
+            out.extend_from_slice(payload);
+        }
+
+        changed.then_some(out)
+    }
+
+    /// The flag, payload and remaining bytes of the first frame.
+    fn split_frame<'a>(&self, content: &'a [u8]) -> Option<(u64, &'a [u8], &'a [u8])> {
+        let header_len = self.flag_bytes + self.length_bytes;
+        if content.len() < header_len {
+            return None;
+        }
+        let flag = decode(&content[..self.flag_bytes], self.little_endian);
+        let len = decode(&content[self.flag_bytes..header_len], self.little_endian);
+        let len = usize::try_from(len).ok()?;
+        let end = header_len.checked_add(len)?;
+        if content.len() < end {
+            return None;
+        }
+        Some((flag, &content[header_len..end], &content[end..]))
+    }
+
+    fn is_modified(&self, flag: u64) -> bool {
+        self.flag_bytes == 0 || self.flags.contains(&flag)
+    }
+
+    /// Whether a payload of `len` bytes can be described by the prefix.
+    fn fits(&self, len: usize) -> bool {
+        self.length_bytes >= 8 || (len as u64) < 1 << (self.length_bytes * 8)
+    }
+}
+
+fn is_text(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.to_lowercase().contains("application/grpc-web-text"))
+}
+
+/// Decodes a gRPC-Web text body. The server may encode each frame on its own
+/// as it flushes it, so every padded segment is decoded separately.
+fn decode_text(content: &[u8]) -> Option<Vec<u8>> {
+    let content: Vec<u8> = content
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    let mut out = vec![];
+    let mut rest = &content[..];
+    while !rest.is_empty() {
+        let end = rest
+            .chunks(4)
+            .position(|quad| quad.contains(&b'='))
+            .map_or(rest.len(), |i| (i * 4 + 4).min(rest.len()));
+        out.extend(base64::decode(&rest[..end]).ok()?);
+        rest = &rest[end..];
+    }
+    Some(out)
+}
+
+fn decode(bytes: &[u8], little_endian: bool) -> u64 {
+    let fold = |n: u64, b: &u8| n << 8 | *b as u64;
+    if little_endian {
+        bytes.iter().rev().fold(0, fold)
+    } else {
+        bytes.iter().fold(0, fold)
+    }
+}
+
+fn encode(n: u64, width: usize, little_endian: bool) -> Vec<u8> {
+    let bytes = &n.to_be_bytes()[8 - width..];
+    if little_endian {
+        bytes.iter().rev().copied().collect()
+    } else {
+        bytes.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn frame_modify() -> FrameModify {
+        serde_json::from_value(serde_json::json!({
+            "body": {"origin": "production", "new": "staging"},
+        }))
+        .unwrap()
+    }
+
+    fn headers(content_type: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers
+    }
+
+    fn frame(flag: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![flag];
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn reframes() {
+        let fm = frame_modify();
+        let headers = headers("application/grpc-web+proto");
+        assert!(fm.is_framed(&headers));
+
+        let trailers = frame(0x80, b"grpc-status:0\r\nenv:production\r\n");
+        let body = [frame(0, b"env=production"), trailers.clone()].concat();
+        let out = fm.exec_action(&headers, &body).unwrap();
+        assert_eq!(out, [frame(0, b"env=staging"), trailers].concat());
+
+        assert!(fm
+            .exec_action(&headers, &frame(0, b"env=staging"))
+            .is_none());
+    }
+
+    #[test]
+    fn grpc_web_text() {
+        let fm = frame_modify();
+        let headers = headers("application/grpc-web-text+proto");
+        assert!(fm.is_framed(&headers));
+
+        // message and trailers encoded separately, as flushed by the server
+        let trailers = frame(0x80, b"grpc-status:0\r\n");
+        let body = [
+            base64::encode(frame(0, b"env=production")),
+            base64::encode(&trailers),
+        ]
+        .concat();
+        let out = fm.exec_action(&headers, body.as_bytes()).unwrap();
+        let out = base64::decode(out).unwrap();
+        assert_eq!(out, [frame(0, b"env=staging"), trailers].concat());
+
+        assert!(fm.exec_action(&headers, b"not base64!").is_none());
+    }
+}
@@ -0,0 +1,118 @@
+use log::error;
+use lol_html::{
+    doc_comments, html_content::Element, rewrite_str, ElementContentHandlers, RewriteStrSettings,
+    Selector,
+};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Removes comments and elements from an html body with a real html
+/// tokenizer, so script contents or attributes can't confuse it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HtmlStrip {
+    #[serde(default)]
+    pub comments: bool,
+    /// CSS selectors of the elements to remove along with their content.
+    #[serde(default)]
+    pub elements: Vec<CssSelector>,
+}
+
+/// A CSS selector, parsed when the rule is loaded.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CssSelector {
+    raw: String,
+    selector: Selector,
+}
+
+impl TryFrom<String> for CssSelector {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        match raw.parse() {
+            Ok(selector) => Ok(Self { raw, selector }),
+            Err(err) => Err(format!("css selector {} invalid: {}", raw, err)),
+        }
+    }
+}
+
+impl From<CssSelector> for String {
+    fn from(selector: CssSelector) -> Self {
+        selector.raw
+    }
+}
+
+impl CssSelector {
+    pub fn selector(&self) -> &Selector {
+        &self.selector
+    }
+}
+
+impl HtmlStrip {
+    /// Returns the stripped html, or `None` when it couldn't be rewritten.
+    pub fn exec_action(&self, html: &str) -> Option<String> {
+        let element_content_handlers = self
+            .elements
+            .iter()
+            .map(|selector| {
+                (
+                    Cow::Borrowed(selector.selector()),
+                    ElementContentHandlers::default().element(|el: &mut Element| {
+                        el.remove();
+                        Ok(())
+                    }),
+                )
+            })
+            .collect();
+
+        let mut document_content_handlers = vec![];
+        if self.comments {
+            document_content_handlers.push(doc_comments!(|c| {
+                c.remove();
+                Ok(())
+            }));
+        }
+
+        rewrite_str(
+            html,
+            RewriteStrSettings {
+                element_content_handlers,
+                document_content_handlers,
+                ..RewriteStrSettings::new()
+            },
+        )
+        .map_err(|err| error!("html rewrite failed: {}", err))
+        .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(json: serde_json::Value) -> Result<HtmlStrip, serde_json::Error> {
+        serde_json::from_value(json)
+    }
+
+    #[test]
+    fn strips() {
+        let strip = strip(serde_json::json!({
+            "comments": true,
+            "elements": ["script", "div.ad"],
+        }))
+        .unwrap();
+        let html = r#"<p>a<!-- x --></p><script>"<div class=ad>"</script><div class="ad">b</div>"#;
+        assert_eq!(strip.exec_action(html).unwrap(), "<p>a</p>");
+    }
+
+    #[test]
+    fn selector_invalid() {
+        let err = strip(serde_json::json!({ "elements": ["div["] })).unwrap_err();
+        assert!(
+            err.to_string().contains("css selector div[ invalid"),
+            "{}",
+            err
+        );
+    }
+}
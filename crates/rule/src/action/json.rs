@@ -0,0 +1,645 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{collections::HashSet, ops::Range};
+
+use super::modify::TextModify;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JsonFormat {
+    /// Sort object keys recursively, keeping array order.
+    SortKeys,
+}
+
+impl JsonFormat {
+    /// Returns the formatted body, or `None` when it isn't valid JSON.
+    pub fn exec_action(&self, content: &[u8]) -> Option<String> {
+        let value: Value = serde_json::from_slice(content).ok()?;
+        match self {
+            JsonFormat::SortKeys => serde_json::to_string(&sort_keys(value)).ok(),
+        }
+    }
+}
+
+fn sort_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(String, Value)> = map.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k, sort_keys(v)))
+                    .collect::<Map<String, Value>>(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(sort_keys).collect()),
+        value => value,
+    }
+}
+
+/// A path into a JSON document such as `$.data.items[*].id`.
+///
+/// Supports `.key`, `['key']`, `[index]` and the `[*]` / `.*` wildcard, the
+/// leading `$` is optional.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct JsonPath {
+    raw: String,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Key(String),
+    Index(usize),
+    Wildcard,
+}
+
+impl TryFrom<String> for JsonPath {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("json path invalid: {}", raw);
+        let mut steps = vec![];
+        let mut rest = raw.strip_prefix('$').unwrap_or(&raw);
+
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                let key = &after[..end];
+                steps.push(match key {
+                    "" => return Err(invalid()),
+                    "*" => Step::Wildcard,
+                    key => Step::Key(key.to_owned()),
+                });
+                rest = &after[end..];
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let end = after.find(']').ok_or_else(invalid)?;
+                let inner = after[..end].trim();
+                steps.push(if inner == "*" {
+                    Step::Wildcard
+                } else if let Some(key) = inner
+                    .strip_prefix('\'')
+                    .and_then(|k| k.strip_suffix('\''))
+                    .or_else(|| inner.strip_prefix('"').and_then(|k| k.strip_suffix('"')))
+                {
+                    Step::Key(key.to_owned())
+                } else {
+                    Step::Index(inner.parse().map_err(|_| invalid())?)
+                });
+                rest = &after[end + 1..];
+            } else if steps.is_empty() && raw.starts_with(|c| c != '$') {
+                // a bare `data.items` without the leading `$.`
+                let end = rest.find(['.', '[']).unwrap_or(rest.len());
+                steps.push(Step::Key(rest[..end].to_owned()));
+                rest = &rest[end..];
+            } else {
+                return Err(invalid());
+            }
+        }
+
+        Ok(Self { raw, steps })
+    }
+}
+
+impl From<JsonPath> for String {
+    fn from(path: JsonPath) -> Self {
+        path.raw
+    }
+}
+
+impl JsonPath {
+    /// Calls `f` on every node the path selects, returning how many there
+    /// were. Missing keys and out of range indices select nothing.
+    pub fn for_each_mut(&self, value: &mut Value, f: &mut dyn FnMut(&mut Value)) -> usize {
+        walk(&self.steps, value, f)
+    }
+
+    /// Calls `f` on every node the path selects in the JSON `text`, and puts
+    /// the nodes it changed back in place of their original text. Everything
+    /// else, whitespace and number formatting included, is kept byte for
+    /// byte. Returns the edited text and how many nodes were selected, or
+    /// `None` when the node spans can't be tracked, e.g. with duplicate keys.
+    ///
+    /// `text` must already be known to be valid JSON.
+    pub fn splice(&self, text: &str, f: &mut dyn FnMut(&mut Value)) -> Option<(String, usize)> {
+        let mut spans = vec![];
+        find_spans(&self.steps, text, skip_ws(text.as_bytes(), 0), &mut spans)?;
+
+        let mut out = String::with_capacity(text.len());
+        let mut last = 0;
+        for span in &spans {
+            let original = &text[span.clone()];
+            let mut node: Value = serde_json::from_str(original).ok()?;
+            let before = node.clone();
+            f(&mut node);
+            out.push_str(&text[last..span.start]);
+            if node == before {
+                out.push_str(original);
+            } else {
+                out.push_str(&serde_json::to_string(&node).ok()?);
+            }
+            last = span.end;
+        }
+        out.push_str(&text[last..]);
+        Some((out, spans.len()))
+    }
+
+    /// The nodes the path selects.
+    pub fn select<'a>(&self, value: &'a Value) -> Vec<&'a Value> {
+        let mut nodes = vec![value];
+        for step in &self.steps {
+            nodes = nodes
+                .into_iter()
+                .flat_map(|node| -> Vec<&Value> {
+                    match (step, node) {
+                        (Step::Key(key), Value::Object(map)) => map.get(key).into_iter().collect(),
+                        (Step::Index(index), Value::Array(items)) => {
+                            items.get(*index).into_iter().collect()
+                        }
+                        (Step::Wildcard, Value::Object(map)) => map.values().collect(),
+                        (Step::Wildcard, Value::Array(items)) => items.iter().collect(),
+                        _ => vec![],
+                    }
+                })
+                .collect();
+        }
+        nodes
+    }
+}
+
+fn walk(steps: &[Step], value: &mut Value, f: &mut dyn FnMut(&mut Value)) -> usize {
+    let (step, rest) = match steps.split_first() {
+        Some(split) => split,
+        None => {
+            f(value);
+            return 1;
+        }
+    };
+    match (step, value) {
+        (Step::Key(key), Value::Object(map)) => match map.get_mut(key) {
+            Some(child) => walk(rest, child, f),
+            None => 0,
+        },
+        (Step::Index(index), Value::Array(items)) => match items.get_mut(*index) {
+            Some(child) => walk(rest, child, f),
+            None => 0,
+        },
+        (Step::Wildcard, Value::Object(map)) => {
+            map.values_mut().map(|child| walk(rest, child, f)).sum()
+        }
+        (Step::Wildcard, Value::Array(items)) => {
+            items.iter_mut().map(|child| walk(rest, child, f)).sum()
+        }
+        _ => 0,
+    }
+}
+
+/// Pushes the byte spans of the nodes `steps` select from the value starting
+/// at `start`, in document order.
+fn find_spans(
+    steps: &[Step],
+    text: &str,
+    start: usize,
+    spans: &mut Vec<Range<usize>>,
+) -> Option<()> {
+    let (step, rest) = match steps.split_first() {
+        Some(split) => split,
+        None => {
+            spans.push(start..value_end(text.as_bytes(), start)?);
+            return Some(());
+        }
+    };
+    let is_object = text.as_bytes().get(start) == Some(&b'{');
+    let is_array = text.as_bytes().get(start) == Some(&b'[');
+    let children = children(text, start)?;
+    match step {
+        Step::Key(key) if is_object => {
+            if let Some((_, child)) = children.iter().find(|(k, _)| k.as_ref() == Some(key)) {
+                find_spans(rest, text, *child, spans)?;
+            }
+        }
+        Step::Index(index) if is_array => {
+            if let Some((_, child)) = children.get(*index) {
+                find_spans(rest, text, *child, spans)?;
+            }
+        }
+        Step::Wildcard => {
+            for (_, child) in children {
+                find_spans(rest, text, child, spans)?;
+            }
+        }
+        _ => {}
+    }
+    Some(())
+}
+
+/// The keys, for objects, and start offsets of the children of the value at
+/// `start`. Scalars have none. `None` when an object repeats a key, as the
+/// parsed value would only keep the last one.
+fn children(text: &str, start: usize) -> Option<Vec<(Option<String>, usize)>> {
+    let bytes = text.as_bytes();
+    let close = match bytes.get(start)? {
+        b'{' => b'}',
+        b'[' => b']',
+        _ => return Some(vec![]),
+    };
+
+    let mut children = vec![];
+    let mut keys = HashSet::new();
+    let mut i = skip_ws(bytes, start + 1);
+    if *bytes.get(i)? == close {
+        return Some(children);
+    }
+    loop {
+        let key = if close == b'}' {
+            let end = string_end(bytes, i)?;
+            let key: String = serde_json::from_str(&text[i..end]).ok()?;
+            if !keys.insert(key.clone()) {
+                return None;
+            }
+            i = skip_ws(bytes, end);
+            if *bytes.get(i)? != b':' {
+                return None;
+            }
+            i = skip_ws(bytes, i + 1);
+            Some(key)
+        } else {
+            None
+        };
+        children.push((key, i));
+
+        i = skip_ws(bytes, value_end(bytes, i)?);
+        match *bytes.get(i)? {
+            b',' => i = skip_ws(bytes, i + 1),
+            c if c == close => return Some(children),
+            _ => return None,
+        }
+    }
+}
+
+fn skip_ws(bytes: &[u8], mut i: usize) -> usize {
+    while bytes.get(i).is_some_and(|b| b.is_ascii_whitespace()) {
+        i += 1;
+    }
+    i
+}
+
+/// The end of the string starting with the quote at `start`.
+fn string_end(bytes: &[u8], start: usize) -> Option<usize> {
+    if bytes.get(start) != Some(&b'"') {
+        return None;
+    }
+    let mut i = start + 1;
+    loop {
+        match bytes.get(i)? {
+            b'\\' => i += 2,
+            b'"' => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+}
+
+/// The end of the value starting at `start`.
+fn value_end(bytes: &[u8], start: usize) -> Option<usize> {
+    match bytes.get(start)? {
+        b'"' => string_end(bytes, start),
+        b'{' | b'[' => {
+            let mut depth = 0;
+            let mut i = start;
+            loop {
+                match bytes.get(i)? {
+                    b'"' => {
+                        i = string_end(bytes, i)?;
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(i + 1);
+                        }
+                    }
+                    _ => {}
+                }
+                i += 1;
+            }
+        }
+        _ => {
+            let len = bytes[start..]
+                .iter()
+                .position(|b| matches!(b, b',' | b'}' | b']') || b.is_ascii_whitespace())
+                .unwrap_or(bytes.len() - start);
+            (len > 0).then_some(start + len)
+        }
+    }
+}
+
+/// Rewrites the nodes at `path` with a [`TextModify`], leaving the rest of the
+/// JSON alone.
+///
+/// String nodes are modified as their text and stay strings. Other nodes are
+/// modified as their JSON text, and the result is parsed back as JSON, so a
+/// price can be set to `9.99`, falling back to a string when it isn't valid
+/// JSON.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct JsonModify {
+    pub path: JsonPath,
+    pub value: TextModify,
+}
+
+impl JsonModify {
+    /// Returns the modified body, or `None` when it isn't valid JSON or the
+    /// path selects nothing.
+    ///
+    /// Only the changed nodes are rewritten, see [`JsonPath::splice`], unless
+    /// their spans can't be tracked and the whole body is reserialized.
+    pub fn exec_action(&self, content: &[u8]) -> Option<String> {
+        let mut value: Value = serde_json::from_slice(content).ok()?;
+        let mut modify = |node: &mut Value| {
+            let new = match &*node {
+                Value::String(text) => Value::String(self.value.exec_action(text)),
+                other => {
+                    let text = self.value.exec_action(&other.to_string());
+                    serde_json::from_str(&text).unwrap_or(Value::String(text))
+                }
+            };
+            *node = new;
+        };
+
+        // valid JSON is valid UTF-8
+        let text = std::str::from_utf8(content).ok()?;
+        if let Some((text, count)) = self.path.splice(text, &mut modify) {
+            return (count > 0).then_some(text);
+        }
+
+        let count = self.path.for_each_mut(&mut value, &mut modify);
+        if count == 0 {
+            return None;
+        }
+        serde_json::to_string(&value).ok()
+    }
+}
+
+/// Changes the type of the nodes at `path` to exercise how clients cope with
+/// unexpected JSON, e.g. a string id turned into a number.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct JsonCorrupt {
+    pub path: JsonPath,
+    pub to: JsonType,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JsonType {
+    /// The node serialized as JSON text, strings are kept as they are.
+    String,
+    /// Numeric strings are parsed, `true` / `false` become `1` / `0`,
+    /// anything else becomes `0`.
+    Number,
+    Bool,
+    Null,
+    /// The node wrapped in a single element array.
+    Array,
+    /// An empty object.
+    Object,
+}
+
+impl JsonCorrupt {
+    /// Returns the corrupted body, or `None` when it isn't valid JSON or the
+    /// path selects nothing.
+    ///
+    /// Only the changed nodes are rewritten, see [`JsonPath::splice`], unless
+    /// their spans can't be tracked and the whole body is reserialized.
+    pub fn exec_action(&self, content: &[u8]) -> Option<String> {
+        let mut value: Value = serde_json::from_slice(content).ok()?;
+        let mut corrupt = |node: &mut Value| *node = self.to.convert(node.take());
+
+        // valid JSON is valid UTF-8
+        let text = std::str::from_utf8(content).ok()?;
+        if let Some((text, count)) = self.path.splice(text, &mut corrupt) {
+            return (count > 0).then_some(text);
+        }
+
+        let count = self.path.for_each_mut(&mut value, &mut corrupt);
+        if count == 0 {
+            return None;
+        }
+        serde_json::to_string(&value).ok()
+    }
+}
+
+impl JsonType {
+    fn convert(&self, value: Value) -> Value {
+        match self {
+            JsonType::String => match value {
+                Value::String(_) => value,
+                value => Value::String(value.to_string()),
+            },
+            JsonType::Number => match value {
+                Value::Number(_) => value,
+                Value::Bool(b) => Value::from(b as u8),
+                Value::String(ref s) => s
+                    .trim()
+                    .parse::<i64>()
+                    .map(Value::from)
+                    .or_else(|_| s.trim().parse::<f64>().map(Value::from))
+                    .unwrap_or_else(|_| Value::from(0)),
+                _ => Value::from(0),
+            },
+            JsonType::Bool => Value::Bool(match value {
+                Value::Null => false,
+                Value::Bool(b) => b,
+                Value::Number(n) => n.as_f64() != Some(0.0),
+                Value::String(s) => !s.is_empty(),
+                Value::Array(items) => !items.is_empty(),
+                Value::Object(map) => !map.is_empty(),
+            }),
+            JsonType::Null => Value::Null,
+            JsonType::Array => match value {
+                Value::Array(_) => value,
+                value => Value::Array(vec![value]),
+            },
+            JsonType::Object => Value::Object(Map::new()),
+        }
+    }
+}
+
+/// Serves one page of a JSON array, read from the `page` query parameter of
+/// the request, wrapped with pagination fields:
+/// `{"items": [...], "page": 2, "size": 10, "total": 35, "next": 3}`.
+///
+/// The array at `path`, the whole body by default, is replaced by the wrapper.
+/// Pages start at 1, a missing or invalid page is page 1 and pages past the
+/// end have no items. `next` is `null` on the last page.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct JsonPaginate {
+    #[serde(default)]
+    pub path: Option<JsonPath>,
+    #[serde(default = "default_page_param")]
+    pub page_param: String,
+    #[serde(default = "default_page_size")]
+    pub size: usize,
+}
+
+fn default_page_param() -> String {
+    "page".to_owned()
+}
+
+fn default_page_size() -> usize {
+    10
+}
+
+impl JsonPaginate {
+    /// Returns the paginated body, or `None` when it isn't valid JSON or
+    /// there is no array at the path.
+    ///
+    /// With a `path`, the rest of the body keeps its formatting as long as
+    /// the spans can be tracked, see [`JsonPath::splice`].
+    pub fn exec_action(&self, content: &[u8], query: Option<&str>) -> Option<String> {
+        let mut value: Value = serde_json::from_slice(content).ok()?;
+        let page = query
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| *name == self.page_param)
+            .and_then(|(_, page)| page.parse::<usize>().ok())
+            .filter(|page| *page > 0)
+            .unwrap_or(1);
+
+        let mut paged = 0;
+        let mut paginate = |node: &mut Value| {
+            if let Value::Array(items) = node {
+                *node = self.page_of(std::mem::take(items), page);
+                paged += 1;
+            }
+        };
+        // valid JSON is valid UTF-8
+        let text = std::str::from_utf8(content).ok()?;
+        let spliced = match self.path {
+            Some(ref path) => match path.splice(text, &mut paginate) {
+                Some((text, _)) => Some(text),
+                None => {
+                    path.for_each_mut(&mut value, &mut paginate);
+                    None
+                }
+            },
+            None => {
+                paginate(&mut value);
+                None
+            }
+        };
+        if paged == 0 {
+            return None;
+        }
+        match spliced {
+            Some(text) => Some(text),
+            None => serde_json::to_string(&value).ok(),
+        }
+    }
+
+    fn page_of(&self, items: Vec<Value>, page: usize) -> Value {
+        let size = self.size.max(1);
+        let total = items.len();
+        let start = (page - 1).saturating_mul(size);
+        let next = if start.saturating_add(size) < total {
+            Value::from(page + 1)
+        } else {
+            Value::Null
+        };
+        let items: Vec<Value> = items.into_iter().skip(start).take(size).collect();
+        serde_json::json!({
+            "items": items,
+            "page": page,
+            "size": size,
+            "total": total,
+            "next": next,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modify(path: &str, value: Value) -> JsonModify {
+        serde_json::from_value(serde_json::json!({ "path": path, "value": value })).unwrap()
+    }
+
+    #[test]
+    fn path_steps() {
+        let path = JsonPath::try_from("$.data['items'][*].id".to_owned()).unwrap();
+        assert_eq!(
+            path.steps,
+            [
+                Step::Key("data".to_owned()),
+                Step::Key("items".to_owned()),
+                Step::Wildcard,
+                Step::Key("id".to_owned()),
+            ]
+        );
+        let path = JsonPath::try_from("data.*[1]".to_owned()).unwrap();
+        assert_eq!(
+            path.steps,
+            [Step::Key("data".to_owned()), Step::Wildcard, Step::Index(1)]
+        );
+        assert!(JsonPath::try_from("$..a".to_owned()).is_err());
+        assert!(JsonPath::try_from("$[1".to_owned()).is_err());
+    }
+
+    #[test]
+    fn keeps_whitespace_and_escapes() {
+        let body =
+            "{\n  \"name\" : \"caf\\u00e9\",\n  \"note\": \"a\\nb \\\"q\\\"\",\n  \"n\": 1.50\n}\n";
+        let modified = modify("$.name", "tea".into()).exec_action(body.as_bytes());
+        assert_eq!(
+            modified.unwrap(),
+            "{\n  \"name\" : \"tea\",\n  \"note\": \"a\\nb \\\"q\\\"\",\n  \"n\": 1.50\n}\n"
+        );
+
+        // a node left as it was keeps its original text
+        let unchanged = modify("$.name", serde_json::json!({"re": "x", "new": "y"}));
+        assert_eq!(unchanged.exec_action(body.as_bytes()).unwrap(), body);
+    }
+
+    #[test]
+    fn wildcard_paths() {
+        let body =
+            r#"{"items": [{"id": 1}, {"id": 2}, {"name": "x"}], "meta": {"a": "1", "b": "2"}}"#;
+        let modified = modify("$.items[*].id", "7".into()).exec_action(body.as_bytes());
+        assert_eq!(
+            modified.unwrap(),
+            r#"{"items": [{"id": 7}, {"id": 7}, {"name": "x"}], "meta": {"a": "1", "b": "2"}}"#
+        );
+
+        let modified = modify("$.meta.*", "0".into()).exec_action(body.as_bytes());
+        assert_eq!(
+            modified.unwrap(),
+            r#"{"items": [{"id": 1}, {"id": 2}, {"name": "x"}], "meta": {"a": "0", "b": "0"}}"#
+        );
+    }
+
+    #[test]
+    fn missing_path() {
+        let body = br#"{"items": [{"id": 1}]}"#;
+        assert!(modify("$.missing", "x".into()).exec_action(body).is_none());
+        assert!(modify("$.items[3].id", "x".into())
+            .exec_action(body)
+            .is_none());
+        assert!(modify("$.items.id", "x".into()).exec_action(body).is_none());
+        assert!(modify("$.items", "x".into())
+            .exec_action(b"not json")
+            .is_none());
+    }
+
+    #[test]
+    fn duplicate_keys_reserialized() {
+        let body = br#"{"a": 1, "a": 2}"#;
+        let modified = modify("$.a", "3".into()).exec_action(body);
+        assert_eq!(modified.unwrap(), r#"{"a":3}"#);
+    }
+}
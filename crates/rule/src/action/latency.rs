@@ -0,0 +1,50 @@
+use hyper::{Body, Response, StatusCode};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Pads responses that arrive faster than `floor` milliseconds and optionally
+/// fails the ones slower than `ceiling` milliseconds.
+///
+/// Elapsed time is measured from the moment the rule forwarded the request
+/// until the response headers reach this action.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LatencyFloor {
+    pub floor: u64,
+    #[serde(default)]
+    pub ceiling: Option<u64>,
+}
+
+impl LatencyFloor {
+    pub async fn apply(
+        &self,
+        forwarded_at: Option<Instant>,
+        res: Response<Body>,
+    ) -> Response<Body> {
+        let elapsed = match forwarded_at {
+            Some(forwarded_at) => forwarded_at.elapsed(),
+            None => return res,
+        };
+
+        if let Some(ceiling) = self.ceiling {
+            if elapsed > Duration::from_millis(ceiling) {
+                info!(
+                    "[LatencyFloor] upstream took {}ms, over ceiling {}ms",
+                    elapsed.as_millis(),
+                    ceiling
+                );
+                return Response::builder()
+                    .status(StatusCode::GATEWAY_TIMEOUT)
+                    .body(Body::default())
+                    .unwrap();
+            }
+        }
+
+        let floor = Duration::from_millis(self.floor);
+        if elapsed < floor {
+            tokio::time::sleep(floor - elapsed).await;
+        }
+        res
+    }
+}
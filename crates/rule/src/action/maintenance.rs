@@ -0,0 +1,79 @@
+use http::HeaderValue;
+use hyper::{header, Body, Request, Response, StatusCode};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use super::accept::quality;
+
+/// Answers with a `503 Service Unavailable` maintenance page without
+/// forwarding the request upstream.
+///
+/// Clients that rank `application/json` above `text/html` in `Accept` get the
+/// JSON body, everyone else the HTML one. Each body comes from the inline
+/// text, else the file, which is read on every request so it can be edited
+/// while the proxy runs, else a built-in default.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Maintenance {
+    /// Seconds, sent as `Retry-After`.
+    #[serde(default)]
+    pub retry_after: Option<u64>,
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub body_file: Option<String>,
+    #[serde(default)]
+    pub json: Option<String>,
+    #[serde(default)]
+    pub json_file: Option<String>,
+}
+
+const DEFAULT_HTML: &str = "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Under maintenance</title></head>\n<body><h1>Under maintenance</h1><p>We'll be back shortly.</p></body></html>\n";
+
+impl Maintenance {
+    pub fn build_res(&self, req: &Request<Body>) -> Response<Body> {
+        let wants_json =
+            quality(req.headers(), "application/json") > quality(req.headers(), "text/html");
+        let (body, content_type) = if wants_json {
+            let body = load(&self.json, &self.json_file).unwrap_or_else(|| {
+                serde_json::json!({
+                    "error": "maintenance",
+                    "retry_after": self.retry_after,
+                })
+                .to_string()
+            });
+            (body, "application/json")
+        } else {
+            let body = load(&self.body, &self.body_file).unwrap_or_else(|| DEFAULT_HTML.to_owned());
+            (body, "text/html; charset=utf-8")
+        };
+
+        let mut res = Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, body.len())
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(Body::from(body))
+            .unwrap();
+        if let Some(retry_after) = self.retry_after {
+            res.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        res
+    }
+}
+
+fn load(inline: &Option<String>, file: &Option<String>) -> Option<String> {
+    if let Some(ref body) = inline {
+        return Some(body.clone());
+    }
+    let file = file.as_ref()?;
+    match fs::read_to_string(file) {
+        Ok(body) => Some(body),
+        Err(err) => {
+            error!("maintenance body file {} not read: {}", file, err);
+            None
+        }
+    }
+}
@@ -1,21 +1,71 @@
+mod accept;
+mod anonymize;
+mod attachment;
+mod auth;
+mod budget;
+mod convert;
+mod correlation;
+mod counter;
+mod diff;
+mod encoding;
+mod frame;
+mod html;
 #[cfg(feature = "js")]
 pub mod js;
+mod json;
+mod latency;
 mod log;
+mod maintenance;
 mod modify;
+mod preload;
+mod ratelimit;
+mod respond;
+mod scheme;
+mod status;
+mod stream;
+mod vary;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod websocket;
+mod when;
 
 pub use self::log::*;
-pub use modify::Modify;
+pub(crate) use accept::accepts;
+pub use anonymize::AnonymizeId;
+pub use auth::AuthChallenge;
+pub use budget::BodyBudget;
+pub use correlation::CorrelationId;
+pub use counter::{expand_counters, Counter};
+pub use latency::LatencyFloor;
+pub use maintenance::Maintenance;
+pub use modify::{ConditionalModify, TextTypes};
+pub use ratelimit::{Quota, RateLimit};
+pub use respond::Respond;
 use serde::{Deserialize, Serialize};
+pub use status::OnStatus;
+pub use websocket::WebSocketProtocol;
+pub use when::RequestHead;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum Action {
     Reject,
     Redirect(String),
-    ModifyRequest(Modify),
-    ModifyResponse(Modify),
+    Respond(Respond),
+    ModifyRequest(ConditionalModify),
+    ModifyResponse(ConditionalModify),
     LogRes,
     LogReq,
+    AnonymizeId(AnonymizeId),
+    LatencyFloor(LatencyFloor),
+    WebSocketProtocol(WebSocketProtocol),
+    Counter(Counter),
+    BodyBudget(BodyBudget),
+    CorrelationId(CorrelationId),
+    AuthChallenge(AuthChallenge),
+    OnStatus(OnStatus),
+    RateLimit(RateLimit),
+    Maintenance(Maintenance),
 
     #[cfg(feature = "js")]
     Js(String),
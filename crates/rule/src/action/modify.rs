@@ -1,16 +1,51 @@
-use cookie::{Cookie, CookieJar};
-use http::{header::HeaderName, HeaderValue, Uri};
+use cookie::{time::Duration, Cookie, CookieJar, SameSite};
+use fancy_regex::{NoExpand, Regex};
+use futures_util::StreamExt;
+use http::{
+    header::{HeaderName, InvalidHeaderValue},
+    HeaderValue, Method, Uri,
+};
 use hyper::{body::*, header, Body, HeaderMap, Request, Response, StatusCode};
 use log::error;
-use serde::{Deserialize, Serialize};
-use std::str::FromStr;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::{collections::BTreeMap, str::FromStr};
 
+#[cfg(feature = "wasm")]
+use super::wasm::WasmModify;
+use super::{
+    accept::AcceptRewrite,
+    attachment::Attachment,
+    convert::ConvertBody,
+    diff::{LogDiff, Snapshot},
+    encoding,
+    frame::FrameModify,
+    html::HtmlStrip,
+    json::{JsonCorrupt, JsonFormat, JsonModify, JsonPaginate},
+    preload::Preload,
+    scheme::SchemeRewrite,
+    stream,
+    vary::VaryRewrite,
+    when::{BodyWhen, RequestHead, When},
+};
 use crate::cache::get_regex;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Regexes and their replacements are checked when the rule is loaded, see
+/// [`check_replacement`].
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum TextModify {
     Set(String),
+    /// `(re, new)` pairs applied one after another over the same text, so a
+    /// rule sees the output of the rules before it.
+    MultiRegex(Vec<(String, String)>),
+    /// Adds the text after the existing one, without any separator.
+    Append {
+        append: String,
+    },
+    /// Adds the text before the existing one, without any separator.
+    Prepend {
+        prepend: String,
+    },
     Complex(TextModifyComplex),
 }
 
@@ -20,19 +55,133 @@ pub struct TextModifyComplex {
     pub origin: Option<String>,
     pub re: Option<String>,
     pub new: String,
+    /// Insert `new` as it is, without expanding `$1` or `${name}`.
+    #[serde(default)]
+    pub literal: bool,
+    /// Replace `re` in the body while streaming it, holding back this many
+    /// bytes to catch matches that span chunks, instead of buffering it.
+    #[serde(default)]
+    pub stream_window: Option<usize>,
+}
+
+impl<'de> Deserialize<'de> for TextModify {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged, deny_unknown_fields)]
+        enum Raw {
+            Set(String),
+            MultiRegex(Vec<(String, String)>),
+            Append { append: String },
+            Prepend { prepend: String },
+            Complex(TextModifyComplex),
+        }
+
+        let modify = match Raw::deserialize(deserializer)? {
+            Raw::Set(new) => TextModify::Set(new),
+            Raw::MultiRegex(rules) => TextModify::MultiRegex(rules),
+            Raw::Append { append } => TextModify::Append { append },
+            Raw::Prepend { prepend } => TextModify::Prepend { prepend },
+            Raw::Complex(md) => TextModify::Complex(md),
+        };
+        let checked = match modify {
+            TextModify::Set(_) | TextModify::Append { .. } | TextModify::Prepend { .. } => Ok(()),
+            TextModify::MultiRegex(ref rules) => rules
+                .iter()
+                .try_for_each(|(re, new)| check_replacement(re, new)),
+            TextModify::Complex(ref md) => match (&md.re, md.literal) {
+                (Some(re), false) => check_replacement(re, &md.new),
+                (Some(re), true) => Regex::new(re).map(|_| ()).map_err(|e| e.to_string()),
+                (None, _) => Ok(()),
+            },
+        };
+        checked.map_err(de::Error::custom)?;
+        Ok(modify)
+    }
+}
+
+/// Checks that `re` compiles and that every group `new` refers to as `$1`,
+/// `$name` or `${name}` exists in it, as a missing group would silently
+/// expand to nothing. `$$` is a literal `$`.
+pub(crate) fn check_replacement(re: &str, new: &str) -> Result<(), String> {
+    let regex = Regex::new(re).map_err(|err| format!("regex {} invalid: {}", re, err))?;
+    let names: Vec<&str> = regex.capture_names().flatten().collect();
+
+    let mut rest = new;
+    while let Some(at) = rest.find('$') {
+        rest = &rest[at + 1..];
+        let (name, after) = if let Some(braced) = rest.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                // not a group reference, expanded as written
+                None => continue,
+            }
+        } else if let Some(after) = rest.strip_prefix('$') {
+            rest = after;
+            continue;
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        rest = after;
+        if name.is_empty() {
+            continue;
+        }
+
+        let exists = match name.parse::<usize>() {
+            Ok(index) => index < regex.captures_len(),
+            Err(_) => names.contains(&name),
+        };
+        if !exists {
+            return Err(format!(
+                "replacement {} refers to group ${} missing in regex {}, use $$ for a literal $ or set literal",
+                new, name, re
+            ));
+        }
+    }
+    Ok(())
 }
 
 impl TextModify {
-    fn exec_action(&self, text: &str) -> String {
+    /// The regex, replacement and window of a streaming body replace.
+    fn streaming(&self) -> Option<(&str, &str, usize)> {
+        match self {
+            TextModify::Complex(TextModifyComplex {
+                origin: None,
+                re: Some(re),
+                new,
+                literal: false,
+                stream_window: Some(window),
+            }) => Some((re, new, *window)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn exec_action(&self, text: &str) -> String {
         match self {
             TextModify::Set(new) => new.to_string(),
+            TextModify::MultiRegex(rules) => {
+                let mut text = text.to_owned();
+                for (re, new) in rules {
+                    text = get_regex(re).replace_all(&text, new).to_string();
+                }
+                text
+            }
+            TextModify::Append { append } => format!("{}{}", text, append),
+            TextModify::Prepend { prepend } => format!("{}{}", prepend, text),
             TextModify::Complex(md) => {
                 if let Some(ref origin) = md.origin {
                     return text.replace(origin, &md.new);
                 }
 
                 if let Some(ref re) = md.re {
-                    return get_regex(re).replace_all(text, &md.new).to_string();
+                    let regex = get_regex(re);
+                    return match md.literal {
+                        true => regex.replace_all(text, NoExpand(&md.new)),
+                        false => regex.replace_all(text, &md.new),
+                    }
+                    .to_string();
                 }
 
                 md.new.clone()
@@ -41,7 +190,78 @@ impl TextModify {
     }
 }
 
-#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+/// Reads at most `limit` bytes of `body`. A body over the limit is handed
+/// back whole, the part already read put in front of the rest of the stream.
+pub(crate) async fn read_limited(body: Body, limit: usize) -> hyper::Result<Result<Bytes, Body>> {
+    let mut body = body;
+    let mut chunks: Vec<Bytes> = vec![];
+    let mut size = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        size += chunk.len();
+        chunks.push(chunk);
+        if size > limit {
+            let read = futures_util::stream::iter(chunks.into_iter().map(Ok::<_, hyper::Error>));
+            return Ok(Err(Body::wrap_stream(read.chain(body))));
+        }
+    }
+    Ok(Ok(chunks.concat().into()))
+}
+
+/// The `Content-Type`s whose bodies text modifies rewrite, matched as
+/// substrings ignoring case. `text` and `javascript` by default.
+#[derive(Debug, Clone)]
+pub struct TextTypes(Vec<String>);
+
+impl Default for TextTypes {
+    fn default() -> Self {
+        Self(vec!["text".to_owned(), "javascript".to_owned()])
+    }
+}
+
+impl TextTypes {
+    pub fn new(types: Vec<String>) -> Self {
+        Self(types.into_iter().map(|t| t.to_lowercase()).collect())
+    }
+
+    /// Whether the body declared by these headers is text that rules may
+    /// rewrite.
+    pub(crate) fn matches(&self, headers: &HeaderMap) -> bool {
+        match headers.get(header::CONTENT_TYPE) {
+            Some(content_type) => {
+                let content_type = content_type.to_str().unwrap_or_default().to_lowercase();
+                self.0.iter().any(|t| content_type.contains(t.as_str()))
+            }
+            None => false,
+        }
+    }
+}
+
+/// Whether the body declared by these headers is html.
+pub(crate) fn is_html_body(headers: &HeaderMap) -> bool {
+    match headers.get(header::CONTENT_TYPE) {
+        Some(content_type) => content_type
+            .to_str()
+            .unwrap_or_default()
+            .to_lowercase()
+            .contains("html"),
+        None => false,
+    }
+}
+
+/// Whether the body declared by these headers is JSON.
+pub(crate) fn is_json_body(headers: &HeaderMap) -> bool {
+    match headers.get(header::CONTENT_TYPE) {
+        Some(content_type) => content_type
+            .to_str()
+            .unwrap_or_default()
+            .to_lowercase()
+            .contains("json"),
+        None => false,
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct MapModify {
     pub key: String,
@@ -49,6 +269,99 @@ pub struct MapModify {
     pub value: Option<TextModify>,
     #[serde(default)]
     pub remove: bool,
+    /// Add the header when it is missing, with `value` run on an empty text.
+    #[serde(default = "default_insert")]
+    pub insert: bool,
+    /// Only set the cookie when the request doesn't carry it already.
+    #[serde(default)]
+    pub set_if_absent: bool,
+    #[serde(flatten)]
+    pub attributes: CookieAttributes,
+}
+
+fn default_insert() -> bool {
+    true
+}
+
+impl Default for MapModify {
+    fn default() -> Self {
+        Self {
+            key: String::new(),
+            value: None,
+            remove: false,
+            insert: default_insert(),
+            set_if_absent: false,
+            attributes: CookieAttributes::default(),
+        }
+    }
+}
+
+/// Attributes set on the `Set-Cookie` of a response cookie modify. Unset
+/// ones keep what upstream sent, an empty `domain` or `path` removes it.
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CookieAttributes {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub same_site: Option<CookieSameSite>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secure: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_only: Option<bool>,
+    /// Seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CookieSameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl CookieAttributes {
+    fn is_set(&self) -> bool {
+        self.domain.is_some()
+            || self.path.is_some()
+            || self.same_site.is_some()
+            || self.secure.is_some()
+            || self.http_only.is_some()
+            || self.max_age.is_some()
+    }
+
+    fn apply(&self, cookie: &mut Cookie<'static>) {
+        match self.domain.as_deref() {
+            Some("") => cookie.unset_domain(),
+            Some(domain) => cookie.set_domain(domain.to_owned()),
+            None => {}
+        }
+        match self.path.as_deref() {
+            Some("") => cookie.unset_path(),
+            Some(path) => cookie.set_path(path.to_owned()),
+            None => {}
+        }
+        if let Some(same_site) = self.same_site {
+            cookie.set_same_site(match same_site {
+                CookieSameSite::Strict => SameSite::Strict,
+                CookieSameSite::Lax => SameSite::Lax,
+                CookieSameSite::None => SameSite::None,
+            });
+        }
+        if let Some(secure) = self.secure {
+            cookie.set_secure(secure);
+        }
+        if let Some(http_only) = self.http_only {
+            cookie.set_http_only(http_only);
+        }
+        if let Some(max_age) = self.max_age {
+            cookie.set_max_age(Duration::seconds(max_age));
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -58,10 +371,452 @@ pub enum Modify {
     Header(MapModify),
     Cookie(MapModify),
     Body(TextModify),
+    JsonFormat(JsonFormat),
+    Json(JsonModify),
+    Scheme(SchemeRewrite),
+    StripHtml(HtmlStrip),
+    Preload(Preload),
+    Attachment(Attachment),
+    Accept(AcceptRewrite),
+    JsonCorrupt(JsonCorrupt),
+    JsonPaginate(JsonPaginate),
+    Vary(VaryRewrite),
+    Convert(ConvertBody),
+    Frame(FrameModify),
+    /// Replaces the response status, see [`Modify::modify_res`].
+    Status(u16),
+    #[cfg(feature = "wasm")]
+    Wasm(WasmModify),
+}
+
+/// A `Modify` that only runs when its `when` predicate matches the headers of
+/// the request or response being modified.
+///
+/// The predicate is checked before anything is buffered, so a skipped body
+/// modify costs nothing.
+///
+/// Keys other than these and the modify are rejected when the rule is
+/// loaded, so a misspelt `when` can't make the modify run unconditionally.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConditionalModify {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<When>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when_body: Option<BodyWhen>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_diff: Option<LogDiff>,
+    /// Bodies larger than this many bytes are passed on untouched instead of
+    /// being buffered whole. Modifies that don't change the body ignore it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_body: Option<usize>,
+    #[serde(flatten)]
+    pub modify: Modify,
+}
+
+impl<'de> Deserialize<'de> for ConditionalModify {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // `deny_unknown_fields` doesn't work with `flatten`, the keys the
+        // modify didn't take are collected instead
+        #[derive(Deserialize)]
+        #[serde(rename_all = "kebab-case")]
+        struct Raw {
+            #[serde(default)]
+            when: Option<When>,
+            #[serde(default)]
+            when_body: Option<BodyWhen>,
+            #[serde(default)]
+            log_diff: Option<LogDiff>,
+            #[serde(default)]
+            max_body: Option<usize>,
+            #[serde(flatten)]
+            modify: Modify,
+            #[serde(flatten)]
+            unknown: BTreeMap<String, de::IgnoredAny>,
+        }
+
+        let raw = Raw::deserialize(deserializer)?;
+        if let Some(key) = raw.unknown.keys().next() {
+            return Err(de::Error::custom(format!(
+                "unknown field `{}`, expected `when`, `when-body`, `log-diff`, `max-body` \
+                 or a single modify",
+                key
+            )));
+        }
+        Ok(Self {
+            when: raw.when,
+            when_body: raw.when_body,
+            log_diff: raw.log_diff,
+            max_body: raw.max_body,
+            modify: raw.modify,
+        })
+    }
+}
+
+impl ConditionalModify {
+    pub async fn modify_req(&self, req: Request<Body>, types: &TextTypes) -> Option<Request<Body>> {
+        if let Some(ref when) = self.when {
+            if !when.is_match(req.headers(), &RequestHead::of(&req)) {
+                return Some(req);
+            }
+        }
+        let req = match self.when_body {
+            Some(ref when_body) => {
+                let (parts, body) = req.into_parts();
+                // req body read failed
+                let (body, matched) = when_body.check(&parts.headers, body).await.ok()?;
+                let req = Request::from_parts(parts, body);
+                if !matched {
+                    return Some(req);
+                }
+                req
+            }
+            None => req,
+        };
+        let req = match (self.max_body, self.modify.touches_body()) {
+            (Some(limit), true) => {
+                let (parts, body) = req.into_parts();
+                // req body read failed
+                let (body, fits) = within_limit(&parts.headers, body, limit).await.ok()?;
+                let req = Request::from_parts(parts, body);
+                if !fits {
+                    return Some(req);
+                }
+                req
+            }
+            _ => req,
+        };
+
+        let log_diff = match self.log_diff {
+            Some(ref log_diff) => log_diff,
+            None => {
+                return self
+                    .modify
+                    .modify_req(req, types, self.decoded_limit())
+                    .await
+            }
+        };
+        let with_body = self.modify.touches_body();
+        let limit = self.snapshot_limit(log_diff);
+        // req body read failed
+        let (req, before) = Snapshot::of_req(req, with_body, limit).await.ok()?;
+        let req = self
+            .modify
+            .modify_req(req, types, self.decoded_limit())
+            .await?;
+        let (req, after) = Snapshot::of_req(req, with_body, limit).await.ok()?;
+        log_diff.log(&before, &after);
+        Some(req)
+    }
+
+    /// `head` is that of the request this response answers.
+    pub async fn modify_res(
+        &self,
+        head: &RequestHead,
+        res: Response<Body>,
+        types: &TextTypes,
+    ) -> Response<Body> {
+        if let Some(ref when) = self.when {
+            if !when.is_match(res.headers(), head) {
+                return res;
+            }
+        }
+        let res = match self.when_body {
+            Some(ref when_body) => {
+                let (parts, body) = res.into_parts();
+                let (body, matched) = match when_body.check(&parts.headers, body).await {
+                    Ok(checked) => checked,
+                    Err(err) => return bad_gateway(err),
+                };
+                let res = Response::from_parts(parts, body);
+                if !matched {
+                    return res;
+                }
+                res
+            }
+            None => res,
+        };
+        let res = match (self.max_body, self.modify.touches_body()) {
+            (Some(limit), true) => {
+                let (parts, body) = res.into_parts();
+                let (body, fits) = match within_limit(&parts.headers, body, limit).await {
+                    Ok(checked) => checked,
+                    Err(err) => return bad_gateway(err),
+                };
+                let res = Response::from_parts(parts, body);
+                if !fits {
+                    return res;
+                }
+                res
+            }
+            _ => res,
+        };
+
+        let log_diff = match self.log_diff {
+            Some(ref log_diff) => log_diff,
+            None => {
+                return self
+                    .modify
+                    .modify_res(head, res, types, self.decoded_limit())
+                    .await
+            }
+        };
+        let with_body = self.modify.touches_body();
+        let limit = self.snapshot_limit(log_diff);
+        let (res, before) = match Snapshot::of_res(res, with_body, limit).await {
+            Ok(snapshot) => snapshot,
+            Err(err) => return bad_gateway(err),
+        };
+        let res = self
+            .modify
+            .modify_res(head, res, types, self.decoded_limit())
+            .await;
+        let (res, after) = match Snapshot::of_res(res, with_body, limit).await {
+            Ok(snapshot) => snapshot,
+            Err(err) => return bad_gateway(err),
+        };
+        log_diff.log(&before, &after);
+        res
+    }
+
+    /// Compressed bodies are decompressed up to `max_body` as well.
+    fn decoded_limit(&self) -> usize {
+        self.max_body.unwrap_or(encoding::DECODED_LIMIT)
+    }
+
+    /// Bodies are captured for the diff up to the smaller of the two limits,
+    /// anything larger keeps streaming.
+    fn snapshot_limit(&self, log_diff: &LogDiff) -> usize {
+        match self.max_body {
+            Some(max_body) => max_body.min(log_diff.limit),
+            None => log_diff.limit,
+        }
+    }
+}
+
+/// Buffers `body` when it has at most `limit` bytes, telling whether it did.
+/// A larger body is handed back streaming, read no further than the limit,
+/// or not at all when its `Content-Length` is over it.
+pub(crate) async fn within_limit(
+    headers: &HeaderMap,
+    body: Body,
+    limit: usize,
+) -> hyper::Result<(Body, bool)> {
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > limit) {
+        return Ok((body, false));
+    }
+
+    match read_limited(body, limit).await? {
+        Ok(content) => Ok((Body::from(content), true)),
+        Err(body) => Ok((body, false)),
+    }
+}
+
+/// Reads the whole body, undoing its `Content-Encoding` the way the body
+/// modify does, see [`encoding::decode`]. A coding that isn't supported, or
+/// a body that decodes to over `limit` bytes, leaves the body and headers as
+/// they were.
+async fn read_decoded(headers: &mut HeaderMap, body: Body, limit: usize) -> hyper::Result<Bytes> {
+    let content = to_bytes(body).await?;
+    Ok(encoding::decode(headers, content, limit).unwrap_or_else(|content| content))
+}
+
+/// Keeps a declared `Content-Length` in step with a replaced body of known
+/// length. A body sent without one, i.e. chunked, is left without, and so is
+/// a body whose length didn't change, `before` being that of the original.
+fn sync_content_length(headers: &mut HeaderMap, before: Option<u64>, body: &Body) {
+    let len = match body.size_hint().exact() {
+        Some(len) if Some(len) != before => len,
+        _ => return,
+    };
+    if let Some(value) = headers.get_mut(header::CONTENT_LENGTH) {
+        *value = HeaderValue::from(len);
+    }
+}
+
+/// Whether the `Content-Length` of this response describes a body it
+/// doesn't carry, so it must never be synced with the body.
+fn declares_other_body(head: &RequestHead, status: StatusCode) -> bool {
+    head.method == Method::HEAD
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+}
+
+/// Adds the cookies of a `Cookie` header to `jar`, skipping malformed ones.
+fn add_cookies(jar: &mut CookieJar, cookies: &str) {
+    for c in cookies.split("; ") {
+        if let Ok(c) = Cookie::parse(c.to_owned()) {
+            jar.add(c);
+        }
+    }
+}
+
+/// The pairs of the `Cookie` headers, kept as the bytes they came in, so
+/// pairs that aren't valid text or don't parse are sent on as they were.
+struct CookiePairs(Vec<Vec<u8>>);
+
+impl CookiePairs {
+    fn of(headers: &HeaderMap) -> Self {
+        let pairs = headers
+            .get_all(header::COOKIE)
+            .iter()
+            .flat_map(|v| v.as_bytes().split(|b| *b == b';'))
+            .map(|pair| pair.trim_ascii().to_vec())
+            .filter(|pair| !pair.is_empty())
+            .collect();
+        Self(pairs)
+    }
+
+    fn parse(pair: &[u8]) -> Option<Cookie<'_>> {
+        std::str::from_utf8(pair)
+            .ok()
+            .and_then(|pair| Cookie::parse(pair).ok())
+    }
+
+    fn is_named(pair: &[u8], key: &str) -> bool {
+        Self::parse(pair).is_some_and(|c| c.name() == key)
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        self.0
+            .iter()
+            .filter_map(|pair| Self::parse(pair))
+            .find(|c| c.name() == key)
+            .map(|c| c.value().to_owned())
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.0.retain(|pair| !Self::is_named(pair, key));
+    }
+
+    /// Replaces the cookie named `key` in place, or adds it at the end.
+    fn set(&mut self, key: &str, value: String) {
+        let at = self.0.iter().position(|pair| Self::is_named(pair, key));
+        self.remove(key);
+        let pair = Cookie::new(key.to_owned(), value).to_string().into_bytes();
+        match at {
+            Some(at) => self.0.insert(at, pair),
+            None => self.0.push(pair),
+        }
+    }
+
+    /// The pairs joined into one header, `Ok(None)` when there are none.
+    fn header(&self) -> Result<Option<HeaderValue>, InvalidHeaderValue> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        HeaderValue::from_bytes(&self.0.join(&b"; "[..])).map(Some)
+    }
+}
+
+/// Writes `cookies` back as the `Cookie` header, removing it when empty.
+fn set_cookie_header(headers: &mut HeaderMap, cookies: Option<HeaderValue>) {
+    match cookies {
+        Some(cookies) => headers.insert(header::COOKIE, cookies),
+        None => headers.remove(header::COOKIE),
+    };
+}
+
+/// A `Set-Cookie` value, the parsed cookie when a modify changed it.
+enum SetCookie {
+    Raw(HeaderValue),
+    Modified(Cookie<'static>),
+}
+
+impl SetCookie {
+    fn parse(sc: &HeaderValue) -> Option<Cookie<'static>> {
+        sc.to_str()
+            .ok()
+            .and_then(|sc| Cookie::parse(sc.to_owned()).ok())
+    }
+
+    fn is_named(sc: &HeaderValue, key: &str) -> bool {
+        Self::parse(sc).is_some_and(|c| c.name() == key)
+    }
+}
+
+/// The `Set-Cookie` values a modify produced. Values it didn't change are
+/// kept as they arrived, malformed ones included. Of the changed ones that
+/// end up with the same name, path and domain only the last is kept, as a
+/// browser would.
+fn set_cookie_values(set_cookies: Vec<SetCookie>) -> Option<Vec<HeaderValue>> {
+    let mut seen = vec![];
+    let mut values = vec![];
+    for sc in set_cookies.into_iter().rev() {
+        match sc {
+            SetCookie::Raw(sc) => values.push(sc),
+            SetCookie::Modified(c) => {
+                let id = (
+                    c.name().to_owned(),
+                    c.path().map(str::to_owned),
+                    c.domain().map(str::to_owned),
+                );
+                if !seen.contains(&id) {
+                    seen.push(id);
+                    values.push(HeaderValue::from_str(&c.to_string()).ok()?);
+                }
+            }
+        }
+    }
+    values.reverse();
+    Some(values)
+}
+
+fn bad_gateway(err: hyper::Error) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(Body::from(err.to_string()))
+        .unwrap()
 }
 
 impl Modify {
-    pub async fn modify_req(&self, mut req: Request<Body>) -> Option<Request<Body>> {
+    /// Whether this modify may change the body.
+    fn touches_body(&self) -> bool {
+        #[cfg(feature = "wasm")]
+        if let Modify::Wasm(_) = self {
+            return true;
+        }
+        matches!(
+            self,
+            Modify::Body(_)
+                | Modify::JsonFormat(_)
+                | Modify::Json(_)
+                | Modify::JsonCorrupt(_)
+                | Modify::JsonPaginate(_)
+                | Modify::Convert(_)
+                | Modify::Frame(_)
+                | Modify::Scheme(_)
+                | Modify::StripHtml(_)
+        )
+    }
+
+    /// Any `Content-Length` is updated to the length of the modified body.
+    /// Compressed bodies are decompressed up to `limit` bytes.
+    pub async fn modify_req(
+        &self,
+        req: Request<Body>,
+        types: &TextTypes,
+        limit: usize,
+    ) -> Option<Request<Body>> {
+        if !self.touches_body() {
+            return self.apply_req(req, types, limit).await;
+        }
+        let before = req.body().size_hint().exact();
+        let (mut parts, body) = self.apply_req(req, types, limit).await?.into_parts();
+        sync_content_length(&mut parts.headers, before, &body);
+        Some(Request::from_parts(parts, body))
+    }
+
+    async fn apply_req(
+        &self,
+        mut req: Request<Body>,
+        types: &TextTypes,
+        limit: usize,
+    ) -> Option<Request<Body>> {
         match self {
             Modify::Url(md) => {
                 let origin = req.uri().to_string();
@@ -73,22 +828,30 @@ impl Modify {
                 Some(req)
             }
             Modify::Body(bm) => {
-                let (parts, body) = req.into_parts();
-                if match parts.headers.get(header::CONTENT_TYPE) {
-                    Some(content_type) => {
-                        let content_type = content_type.to_str().unwrap_or_default();
-                        content_type.contains("text") || content_type.contains("javascript")
-                    }
-                    None => false,
-                } {
-                    match to_bytes(body).await {
-                        Ok(content) => match String::from_utf8(content.to_vec()) {
-                            Ok(text) => {
+                let (mut parts, body) = req.into_parts();
+                if let (true, false, true, Some((re, new, window))) = (
+                    types.matches(&parts.headers),
+                    encoding::is_encoded(&parts.headers),
+                    encoding::is_utf8_text(&parts.headers),
+                    bm.streaming(),
+                ) {
+                    parts.headers.remove(header::CONTENT_LENGTH);
+                    let body = stream::replace_all(body, get_regex(re), new.to_owned(), window);
+                    return Some(Request::from_parts(parts, body));
+                }
+                if types.matches(&parts.headers) {
+                    let content = to_bytes(body).await;
+                    match content.map(|c| encoding::decode(&mut parts.headers, c, limit)) {
+                        Ok(Ok(content)) => match encoding::decode_text(&parts.headers, &content) {
+                            Some((text, charset)) => {
                                 let text = bm.exec_action(&text);
-                                Some(Request::from_parts(parts, Body::from(text)))
+                                let body = Body::from(encoding::encode_text(&text, charset));
+                                Some(Request::from_parts(parts, body))
                             }
-                            Err(_) => Some(Request::from_parts(parts, Body::from(content))),
+                            None => Some(Request::from_parts(parts, Body::from(content))),
                         },
+                        // unsupported encoding
+                        Ok(Err(content)) => Some(Request::from_parts(parts, Body::from(content))),
                         // req body read failed
                         Err(_) => None,
                     }
@@ -96,6 +859,155 @@ impl Modify {
                     Some(Request::from_parts(parts, body))
                 }
             }
+            Modify::JsonFormat(format) => {
+                let (mut parts, body) = req.into_parts();
+                if !is_json_body(&parts.headers) {
+                    return Some(Request::from_parts(parts, body));
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match format.exec_action(&content) {
+                        Some(text) => Some(Request::from_parts(parts, Body::from(text))),
+                        None => Some(Request::from_parts(parts, Body::from(content))),
+                    },
+                    // req body read failed
+                    Err(_) => None,
+                }
+            }
+            Modify::Convert(cb) => {
+                let (mut parts, body) = req.into_parts();
+                if !cb.is_source(&parts.headers) {
+                    return Some(Request::from_parts(parts, body));
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match cb.exec_action(&content) {
+                        Some(text) => {
+                            parts.headers.insert(
+                                header::CONTENT_TYPE,
+                                HeaderValue::from_static(cb.content_type()),
+                            );
+                            Some(Request::from_parts(parts, Body::from(text)))
+                        }
+                        None => Some(Request::from_parts(parts, Body::from(content))),
+                    },
+                    // req body read failed
+                    Err(_) => None,
+                }
+            }
+            Modify::Frame(fm) => {
+                let (mut parts, body) = req.into_parts();
+                if !fm.is_framed(&parts.headers) {
+                    return Some(Request::from_parts(parts, body));
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match fm.exec_action(&parts.headers, &content) {
+                        Some(framed) => Some(Request::from_parts(parts, Body::from(framed))),
+                        None => Some(Request::from_parts(parts, Body::from(content))),
+                    },
+                    // req body read failed
+                    Err(_) => None,
+                }
+            }
+            #[cfg(feature = "wasm")]
+            Modify::Wasm(wm) => {
+                let (mut parts, body) = req.into_parts();
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => {
+                        let content = wm.exec_action(&mut parts.headers, content).await;
+                        Some(Request::from_parts(parts, Body::from(content)))
+                    }
+                    // req body read failed
+                    Err(_) => None,
+                }
+            }
+            Modify::Json(jm) => {
+                let (mut parts, body) = req.into_parts();
+                if !is_json_body(&parts.headers) {
+                    return Some(Request::from_parts(parts, body));
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match jm.exec_action(&content) {
+                        Some(text) => Some(Request::from_parts(parts, Body::from(text))),
+                        None => Some(Request::from_parts(parts, Body::from(content))),
+                    },
+                    // req body read failed
+                    Err(_) => None,
+                }
+            }
+            Modify::JsonCorrupt(jc) => {
+                let (mut parts, body) = req.into_parts();
+                if !is_json_body(&parts.headers) {
+                    return Some(Request::from_parts(parts, body));
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match jc.exec_action(&content) {
+                        Some(text) => Some(Request::from_parts(parts, Body::from(text))),
+                        None => Some(Request::from_parts(parts, Body::from(content))),
+                    },
+                    // req body read failed
+                    Err(_) => None,
+                }
+            }
+            Modify::Scheme(sm) => {
+                sm.modify_headers(req.headers_mut(), &[header::ORIGIN, header::REFERER]);
+                let (mut parts, body) = req.into_parts();
+                if !types.matches(&parts.headers) && !is_json_body(&parts.headers) {
+                    return Some(Request::from_parts(parts, body));
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match String::from_utf8(content.to_vec()) {
+                        Ok(text) => {
+                            let text = sm.exec_action(&text);
+                            Some(Request::from_parts(parts, Body::from(text)))
+                        }
+                        Err(_) => Some(Request::from_parts(parts, Body::from(content))),
+                    },
+                    // req body read failed
+                    Err(_) => None,
+                }
+            }
+            Modify::StripHtml(hm) => {
+                let (mut parts, body) = req.into_parts();
+                if !is_html_body(&parts.headers) {
+                    return Some(Request::from_parts(parts, body));
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match String::from_utf8(content.to_vec())
+                        .ok()
+                        .and_then(|html| hm.exec_action(&html))
+                    {
+                        Some(html) => Some(Request::from_parts(parts, Body::from(html))),
+                        None => Some(Request::from_parts(parts, Body::from(content))),
+                    },
+                    // req body read failed
+                    Err(_) => None,
+                }
+            }
+            Modify::Preload(_) => {
+                error!("preload modify request not supported");
+                Some(req)
+            }
+            Modify::Attachment(_) => {
+                error!("attachment modify request not supported");
+                Some(req)
+            }
+            Modify::JsonPaginate(_) => {
+                error!("json-paginate modify request not supported");
+                Some(req)
+            }
+            Modify::Vary(_) => {
+                error!("vary modify request not supported");
+                Some(req)
+            }
+            // a request has no status
+            Modify::Status(_) => {
+                error!("status modify request not supported");
+                Some(req)
+            }
+            Modify::Accept(am) => {
+                let mut req = req;
+                am.modify_headers(req.headers_mut());
+                Some(req)
+            }
             Modify::Header(hm) => {
                 let mut req = req;
                 self.modify_header(req.headers_mut(), hm);
@@ -103,64 +1015,86 @@ impl Modify {
             }
             Modify::Cookie(md) => {
                 let mut req = req;
-                let mut cookies_jar = CookieJar::new();
-
-                if let Some(cookies) = req.headers().get(header::COOKIE) {
-                    let cookies = cookies.to_str().unwrap().to_string();
-                    let cookies: Vec<String> = cookies.split("; ").map(String::from).collect();
-                    for c in cookies {
-                        if let Ok(c) = Cookie::parse(c) {
-                            cookies_jar.add(c);
-                        }
-                    }
+                let mut cookies = CookiePairs::of(req.headers());
+
+                if md.set_if_absent && cookies.get(&md.key).is_some() {
+                    return Some(req);
                 }
 
                 if md.remove {
-                    cookies_jar.remove(Cookie::named(md.key.clone()))
+                    cookies.remove(&md.key)
                 } else {
+                    let origin_cookie_value = cookies.get(&md.key).unwrap_or_default();
                     let new_cookie_value = md
                         .value
-                        .to_owned()
-                        .map(|text_md| {
-                            let origin_cookie_value = cookies_jar
-                                .get(&md.key)
-                                .map(|c| c.value().to_string())
-                                .unwrap_or_default();
-                            text_md.exec_action(&origin_cookie_value)
-                        })
+                        .as_ref()
+                        .map(|text_md| text_md.exec_action(&origin_cookie_value))
                         .unwrap_or_default();
-                    cookies_jar.add(Cookie::new(md.key.clone(), new_cookie_value))
+                    cookies.set(&md.key, new_cookie_value)
                 }
 
-                let cookies: Vec<String> = cookies_jar.iter().map(|c| c.to_string()).collect();
-                let cookies = cookies.join("; ");
-                req.headers_mut()
-                    .insert(header::COOKIE, HeaderValue::from_str(&cookies).unwrap());
+                match cookies.header() {
+                    Ok(cookies) => set_cookie_header(req.headers_mut(), cookies),
+                    Err(_) => error!("modified cookie {} is not a valid header", md.key),
+                }
 
                 Some(req)
             }
         }
     }
 
-    pub async fn modify_res(&self, res: Response<Body>) -> Response<Body> {
+    /// Any `Content-Length` is updated to the length of the modified body.
+    /// Compressed bodies are decompressed up to `limit` bytes.
+    pub async fn modify_res(
+        &self,
+        head: &RequestHead,
+        res: Response<Body>,
+        types: &TextTypes,
+        limit: usize,
+    ) -> Response<Body> {
+        if !self.touches_body() || declares_other_body(head, res.status()) {
+            return self.apply_res(head, res, types, limit).await;
+        }
+        let before = res.body().size_hint().exact();
+        let (mut parts, body) = self.apply_res(head, res, types, limit).await.into_parts();
+        sync_content_length(&mut parts.headers, before, &body);
+        Response::from_parts(parts, body)
+    }
+
+    async fn apply_res(
+        &self,
+        head: &RequestHead,
+        res: Response<Body>,
+        types: &TextTypes,
+        limit: usize,
+    ) -> Response<Body> {
+        let uri = &head.uri;
         match self {
             Modify::Body(bm) => {
-                let (parts, body) = res.into_parts();
-                if match parts.headers.get(header::CONTENT_TYPE) {
-                    Some(content_type) => {
-                        let content_type = content_type.to_str().unwrap_or_default();
-                        content_type.contains("text") || content_type.contains("javascript")
-                    }
-                    None => false,
-                } {
-                    match to_bytes(body).await {
-                        Ok(content) => match String::from_utf8(content.to_vec()) {
-                            Ok(text) => {
+                let (mut parts, body) = res.into_parts();
+                if let (true, false, true, Some((re, new, window))) = (
+                    types.matches(&parts.headers),
+                    encoding::is_encoded(&parts.headers),
+                    encoding::is_utf8_text(&parts.headers),
+                    bm.streaming(),
+                ) {
+                    parts.headers.remove(header::CONTENT_LENGTH);
+                    let body = stream::replace_all(body, get_regex(re), new.to_owned(), window);
+                    return Response::from_parts(parts, body);
+                }
+                if types.matches(&parts.headers) {
+                    let content = to_bytes(body).await;
+                    match content.map(|c| encoding::decode(&mut parts.headers, c, limit)) {
+                        Ok(Ok(content)) => match encoding::decode_text(&parts.headers, &content) {
+                            Some((text, charset)) => {
                                 let text = bm.exec_action(&text);
-                                Response::from_parts(parts, Body::from(text))
+                                let body = Body::from(encoding::encode_text(&text, charset));
+                                Response::from_parts(parts, body)
                             }
-                            Err(_) => Response::from_parts(parts, Body::from(content)),
+                            None => Response::from_parts(parts, Body::from(content)),
                         },
+                        // unsupported encoding
+                        Ok(Err(content)) => Response::from_parts(parts, Body::from(content)),
                         Err(err) => Response::builder()
                             .status(StatusCode::BAD_GATEWAY)
                             .body(Body::from(err.to_string()))
@@ -170,6 +1104,183 @@ impl Modify {
                     Response::from_parts(parts, body)
                 }
             }
+            Modify::JsonFormat(format) => {
+                let (mut parts, body) = res.into_parts();
+                if !is_json_body(&parts.headers) {
+                    return Response::from_parts(parts, body);
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match format.exec_action(&content) {
+                        Some(text) => Response::from_parts(parts, Body::from(text)),
+                        None => Response::from_parts(parts, Body::from(content)),
+                    },
+                    Err(err) => Response::builder()
+                        .status(StatusCode::BAD_GATEWAY)
+                        .body(Body::from(err.to_string()))
+                        .unwrap(),
+                }
+            }
+            Modify::JsonPaginate(jp) => {
+                let (mut parts, body) = res.into_parts();
+                if !is_json_body(&parts.headers) {
+                    return Response::from_parts(parts, body);
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match jp.exec_action(&content, uri.query()) {
+                        Some(text) => Response::from_parts(parts, Body::from(text)),
+                        None => Response::from_parts(parts, Body::from(content)),
+                    },
+                    Err(err) => bad_gateway(err),
+                }
+            }
+            Modify::Convert(cb) => {
+                let (mut parts, body) = res.into_parts();
+                if !cb.is_source(&parts.headers) {
+                    return Response::from_parts(parts, body);
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match cb.exec_action(&content) {
+                        Some(text) => {
+                            parts.headers.insert(
+                                header::CONTENT_TYPE,
+                                HeaderValue::from_static(cb.content_type()),
+                            );
+                            Response::from_parts(parts, Body::from(text))
+                        }
+                        None => Response::from_parts(parts, Body::from(content)),
+                    },
+                    Err(err) => bad_gateway(err),
+                }
+            }
+            Modify::Frame(fm) => {
+                let (mut parts, body) = res.into_parts();
+                if !fm.is_framed(&parts.headers) {
+                    return Response::from_parts(parts, body);
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match fm.exec_action(&parts.headers, &content) {
+                        Some(framed) => Response::from_parts(parts, Body::from(framed)),
+                        None => Response::from_parts(parts, Body::from(content)),
+                    },
+                    Err(err) => bad_gateway(err),
+                }
+            }
+            #[cfg(feature = "wasm")]
+            Modify::Wasm(wm) => {
+                let (mut parts, body) = res.into_parts();
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => {
+                        let content = wm.exec_action(&mut parts.headers, content).await;
+                        Response::from_parts(parts, Body::from(content))
+                    }
+                    Err(err) => bad_gateway(err),
+                }
+            }
+            Modify::Json(jm) => {
+                let (mut parts, body) = res.into_parts();
+                if !is_json_body(&parts.headers) {
+                    return Response::from_parts(parts, body);
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match jm.exec_action(&content) {
+                        Some(text) => Response::from_parts(parts, Body::from(text)),
+                        None => Response::from_parts(parts, Body::from(content)),
+                    },
+                    Err(err) => bad_gateway(err),
+                }
+            }
+            Modify::JsonCorrupt(jc) => {
+                let (mut parts, body) = res.into_parts();
+                if !is_json_body(&parts.headers) {
+                    return Response::from_parts(parts, body);
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match jc.exec_action(&content) {
+                        Some(text) => Response::from_parts(parts, Body::from(text)),
+                        None => Response::from_parts(parts, Body::from(content)),
+                    },
+                    Err(err) => bad_gateway(err),
+                }
+            }
+            Modify::Scheme(sm) => {
+                let mut res = res;
+                sm.modify_headers(
+                    res.headers_mut(),
+                    &[header::LOCATION, header::CONTENT_LOCATION],
+                );
+                let (mut parts, body) = res.into_parts();
+                if !types.matches(&parts.headers) && !is_json_body(&parts.headers) {
+                    return Response::from_parts(parts, body);
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match String::from_utf8(content.to_vec()) {
+                        Ok(text) => {
+                            let text = sm.exec_action(&text);
+                            Response::from_parts(parts, Body::from(text))
+                        }
+                        Err(_) => Response::from_parts(parts, Body::from(content)),
+                    },
+                    Err(err) => bad_gateway(err),
+                }
+            }
+            Modify::StripHtml(hm) => {
+                let (mut parts, body) = res.into_parts();
+                if !is_html_body(&parts.headers) {
+                    return Response::from_parts(parts, body);
+                }
+                match read_decoded(&mut parts.headers, body, limit).await {
+                    Ok(content) => match String::from_utf8(content.to_vec())
+                        .ok()
+                        .and_then(|html| hm.exec_action(&html))
+                    {
+                        Some(html) => Response::from_parts(parts, Body::from(html)),
+                        None => Response::from_parts(parts, Body::from(content)),
+                    },
+                    Err(err) => bad_gateway(err),
+                }
+            }
+            Modify::Preload(pm) => {
+                let (mut parts, body) = res.into_parts();
+                if !is_html_body(&parts.headers) {
+                    return Response::from_parts(parts, body);
+                }
+                match to_bytes(body).await {
+                    Ok(content) => {
+                        // scanned decoded, the body is passed on as it came
+                        let mut headers = parts.headers.clone();
+                        let html = encoding::decode(&mut headers, content.clone(), limit)
+                            .unwrap_or_else(|content| content);
+                        if let Ok(html) = std::str::from_utf8(&html) {
+                            pm.exec_action(html, &mut parts.headers);
+                        }
+                        Response::from_parts(parts, Body::from(content))
+                    }
+                    Err(err) => bad_gateway(err),
+                }
+            }
+            Modify::Attachment(am) => {
+                let mut res = res;
+                res.headers_mut()
+                    .insert(header::CONTENT_DISPOSITION, am.content_disposition(uri));
+                res
+            }
+            Modify::Accept(_) => {
+                error!("accept modify response not supported");
+                res
+            }
+            Modify::Vary(vm) => {
+                let mut res = res;
+                vm.modify_headers(res.headers_mut());
+                res
+            }
+            Modify::Status(code) => {
+                let mut res = res;
+                match StatusCode::from_u16(*code) {
+                    Ok(status) => *res.status_mut() = status,
+                    Err(err) => error!("status {} invalid: {}", code, err),
+                }
+                res
+            }
             Modify::Header(md) => {
                 let mut res = res;
                 self.modify_header(res.headers_mut(), md);
@@ -177,62 +1288,89 @@ impl Modify {
             }
             Modify::Cookie(md) => {
                 let mut res = res;
+                let mut cookies = CookiePairs::of(res.headers());
+                let mut set_cookies: Vec<SetCookie> = res
+                    .headers()
+                    .get_all(header::SET_COOKIE)
+                    .iter()
+                    .map(|sc| SetCookie::Raw(sc.clone()))
+                    .collect();
+                let named = |sc: &SetCookie| match sc {
+                    SetCookie::Raw(sc) => SetCookie::is_named(sc, &md.key),
+                    SetCookie::Modified(_) => false,
+                };
 
-                let mut cookies_jar = CookieJar::new();
-                if let Some(cookies) = res.headers().get(header::COOKIE) {
-                    let cookies = cookies.to_str().unwrap().to_string();
-                    let cookies: Vec<String> = cookies.split("; ").map(String::from).collect();
-                    for c in cookies {
-                        if let Ok(c) = Cookie::parse(c) {
-                            cookies_jar.add(c);
-                        }
+                if md.set_if_absent {
+                    let mut request_jar = CookieJar::new();
+                    if let Some(ref cookies) = head.cookie {
+                        add_cookies(&mut request_jar, cookies);
                     }
-                }
-
-                let mut set_cookies_jar = CookieJar::new();
-                let set_cookies = res.headers().get_all(header::SET_COOKIE);
-                for sc in set_cookies {
-                    let sc = sc.to_str().unwrap().to_string();
-                    if let Ok(c) = Cookie::parse(sc) {
-                        set_cookies_jar.add(c);
+                    // upstream setting the cookie itself wins as well
+                    if request_jar.get(&md.key).is_some() || set_cookies.iter().any(named) {
+                        return res;
                     }
                 }
 
                 if md.remove {
-                    cookies_jar.remove(Cookie::named(md.key.clone()));
-                    set_cookies_jar.remove(Cookie::named(md.key.clone()));
+                    cookies.remove(&md.key);
+                    set_cookies.retain(|sc| !named(sc));
                 } else {
-                    let new_cookie_value = md
-                        .value
-                        .to_owned()
-                        .map(|text_md| {
-                            let origin_cookie_value = cookies_jar
-                                .get(&md.key)
-                                .map(|c| c.value().to_string())
-                                .or_else(|| {
-                                    set_cookies_jar.get(&md.key).map(|c| c.value().to_string())
-                                })
-                                .unwrap_or_default();
-                            text_md.exec_action(&origin_cookie_value)
-                        })
-                        .unwrap_or_default();
+                    let origin_cookie_value = cookies.get(&md.key);
+                    let new_cookie_value = |origin: String| match md.value {
+                        Some(ref text_md) => text_md.exec_action(&origin),
+                        // only the attributes change
+                        None if md.attributes.is_set() => origin,
+                        None => String::new(),
+                    };
 
-                    let c = Cookie::new(md.key.clone(), new_cookie_value);
-                    cookies_jar.add(c.clone());
-                    set_cookies_jar.add(c.clone());
+                    // every cookie of that name, whatever its path or domain,
+                    // keeps the attributes upstream sent unless overridden
+                    let mut found = None;
+                    for sc in set_cookies.iter_mut() {
+                        let mut c = match sc {
+                            SetCookie::Raw(raw) => match SetCookie::parse(raw) {
+                                Some(c) if c.name() == md.key => c,
+                                _ => continue,
+                            },
+                            SetCookie::Modified(_) => continue,
+                        };
+                        let origin = origin_cookie_value.clone();
+                        let value = new_cookie_value(origin.unwrap_or(c.value().to_owned()));
+                        found.get_or_insert_with(|| value.clone());
+                        c.set_value(value);
+                        md.attributes.apply(&mut c);
+                        *sc = SetCookie::Modified(c);
+                    }
+                    let value = match found {
+                        Some(value) => value,
+                        None => {
+                            let value = new_cookie_value(origin_cookie_value.unwrap_or_default());
+                            let mut c = Cookie::new(md.key.clone(), value.clone());
+                            md.attributes.apply(&mut c);
+                            set_cookies.push(SetCookie::Modified(c));
+                            value
+                        }
+                    };
+                    cookies.set(&md.key, value);
                 }
 
-                let cookies: Vec<String> = cookies_jar.iter().map(|c| c.to_string()).collect();
-                let cookies = cookies.join("; ");
+                // build every value first, so the headers are either all
+                // rewritten or left as upstream sent them
+                let set_cookies = set_cookie_values(set_cookies);
+                let (cookies, set_cookies) = match (cookies.header(), set_cookies) {
+                    (Ok(cookies), Some(set_cookies)) => (cookies, set_cookies),
+                    _ => {
+                        error!("modified cookie {} is not a valid header", md.key);
+                        return res;
+                    }
+                };
+
                 let header = res.headers_mut();
-                header.insert(header::COOKIE, HeaderValue::from_str(&cookies).unwrap());
+                set_cookie_header(header, cookies);
 
                 header.remove(header::SET_COOKIE);
-                for sc in set_cookies_jar.iter() {
-                    header.append(
-                        header::SET_COOKIE,
-                        HeaderValue::from_str(&sc.to_string()).unwrap(),
-                    );
+                for sc in set_cookies {
+                    header.append(header::SET_COOKIE, sc);
                 }
 
                 res
@@ -244,20 +1382,378 @@ impl Modify {
         }
     }
 
-    fn modify_header<'a>(&self, header: &mut HeaderMap, md: &'a MapModify) {
+    /// Only the first value of a header sent several times is modified, the
+    /// others are kept as they are.
+    fn modify_header(&self, header: &mut HeaderMap, md: &MapModify) {
         if md.remove {
             header.remove(&md.key);
         } else if let Some(ref text_md) = md.value {
             if let Some(h) = header.get_mut(&md.key) {
                 let new_header_value = text_md.exec_action(h.to_str().unwrap_or_default());
-                *h = header::HeaderValue::from_str(new_header_value.as_str()).unwrap();
-            } else {
+                match HeaderValue::from_str(&new_header_value) {
+                    Ok(value) => *h = value,
+                    Err(_) => error!("header {} value invalid: {}", md.key, new_header_value),
+                }
+            } else if md.insert {
                 let new_header_value = text_md.exec_action("");
-                header.append(
-                    HeaderName::from_str(&md.key).unwrap(),
-                    header::HeaderValue::from_str(new_header_value.as_str()).unwrap(),
-                );
+                match (
+                    HeaderName::from_str(&md.key),
+                    HeaderValue::from_str(&new_header_value),
+                ) {
+                    (Ok(name), Ok(value)) => {
+                        header.append(name, value);
+                    }
+                    _ => error!("header invalid: {}: {}", md.key, new_header_value),
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{encoding::DECODED_LIMIT, *};
+    use flate2::{write::GzEncoder, Compression};
+    use std::{
+        io::Write,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    fn response(headers: &[(&str, &str)], body: impl Into<Body>) -> Response<Body> {
+        let mut res = Response::builder();
+        for (name, value) in headers {
+            res = res.header(*name, *value);
+        }
+        res.body(body.into()).unwrap()
+    }
+
+    fn replace(origin: &str, new: &str) -> Modify {
+        Modify::Body(TextModify::Complex(TextModifyComplex {
+            origin: Some(origin.to_owned()),
+            re: None,
+            new: new.to_owned(),
+            literal: false,
+            stream_window: None,
+        }))
+    }
+
+    async fn modify_res(md: &Modify, res: Response<Body>) -> Response<Body> {
+        md.modify_res(
+            &RequestHead::default(),
+            res,
+            &TextTypes::default(),
+            DECODED_LIMIT,
+        )
+        .await
+    }
+
+    async fn body_of(res: Response<Body>) -> Bytes {
+        to_bytes(res.into_body()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn body_gzip() {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(b"<p>hello world</p>").unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let res = response(
+            &[("content-type", "text/html"), ("content-encoding", "gzip")],
+            gzipped,
+        );
+
+        let res = modify_res(&replace("world", "mitm"), res).await;
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(body_of(res).await, "<p>hello mitm</p>");
+    }
+
+    #[tokio::test]
+    async fn body_brotli() {
+        let mut brotlied = vec![];
+        let mut encoder = brotli::CompressorWriter::new(&mut brotlied, 4096, 5, 22);
+        encoder.write_all(b"<p>hello world</p>").unwrap();
+        drop(encoder);
+        let res = response(
+            &[("content-type", "text/html"), ("content-encoding", "br")],
+            brotlied,
+        );
+
+        let res = modify_res(&replace("world", "mitm"), res).await;
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(body_of(res).await, "<p>hello mitm</p>");
+    }
+
+    #[tokio::test]
+    async fn content_length_shrinks() {
+        let text = "<p>hello world</p>";
+        let res = response(
+            &[("content-type", "text/html"), ("content-length", "18")],
+            text,
+        );
+
+        let res = modify_res(&replace(" world", ""), res).await;
+        let text = "<p>hello</p>";
+        assert_eq!(
+            res.headers()[header::CONTENT_LENGTH],
+            text.len().to_string()
+        );
+        assert_eq!(body_of(res).await, text);
+    }
+
+    #[tokio::test]
+    async fn content_length_chunked() {
+        let chunks = futures_util::stream::iter(vec![
+            Ok::<_, hyper::Error>(Bytes::from("<p>hello ")),
+            Ok::<_, hyper::Error>(Bytes::from("world</p>")),
+        ]);
+        let res = response(&[("content-type", "text/html")], Body::wrap_stream(chunks));
+
+        let res = modify_res(&replace(" world", ""), res).await;
+        assert!(!res.headers().contains_key(header::CONTENT_LENGTH));
+        assert_eq!(body_of(res).await, "<p>hello</p>");
+    }
+
+    #[tokio::test]
+    async fn status() {
+        let res = Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::empty())
+            .unwrap();
+
+        let res = modify_res(&Modify::Status(200), res).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn status_invalid() {
+        let res = Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::empty())
+            .unwrap();
+
+        let res = modify_res(&Modify::Status(1000), res).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    fn set_cookie(key: &str, value: TextModify) -> Modify {
+        Modify::Cookie(MapModify {
+            key: key.to_owned(),
+            value: Some(value),
+            ..Default::default()
+        })
+    }
+
+    fn set_cookies(res: &Response<Body>) -> Vec<&[u8]> {
+        let set_cookies = res.headers().get_all(header::SET_COOKIE);
+        set_cookies.iter().map(|sc| sc.as_bytes()).collect()
+    }
+
+    #[tokio::test]
+    async fn cookie_high_byte() {
+        let mut res = response(&[("set-cookie", "b=2")], Body::empty());
+        let high_byte = HeaderValue::from_bytes(b"a=\xff").unwrap();
+        res.headers_mut().append(header::SET_COOKIE, high_byte);
+
+        let md = set_cookie("c", TextModify::Set("3".to_owned()));
+        let res = modify_res(&md, res).await;
+        assert_eq!(set_cookies(&res), [&b"b=2"[..], b"a=\xff", b"c=3"]);
+    }
+
+    #[tokio::test]
+    async fn cookie_malformed_kept() {
+        let res = response(
+            &[("set-cookie", "=; ;"), ("set-cookie", "a=1")],
+            Body::empty(),
+        );
+
+        let md = set_cookie("a", TextModify::Set("2".to_owned()));
+        let res = modify_res(&md, res).await;
+        assert_eq!(set_cookies(&res), [&b"=; ;"[..], b"a=2"]);
+    }
+
+    #[tokio::test]
+    async fn cookie_paths() {
+        let cookies = [
+            ("set-cookie", "sid=1; Path=/a"),
+            ("set-cookie", "sid=2; Path=/b"),
+        ];
+
+        let md = set_cookie("other", TextModify::Set("3".to_owned()));
+        let res = modify_res(&md, response(&cookies, Body::empty())).await;
+        let expected = [&b"sid=1; Path=/a"[..], b"sid=2; Path=/b", b"other=3"];
+        assert_eq!(set_cookies(&res), expected);
+
+        let md = set_cookie("sid", TextModify::Set("3".to_owned()));
+        let res = modify_res(&md, response(&cookies, Body::empty())).await;
+        assert_eq!(
+            set_cookies(&res),
+            [&b"sid=3; Path=/a"[..], b"sid=3; Path=/b"]
+        );
+    }
+
+    #[tokio::test]
+    async fn cookie_request_high_byte() {
+        let mut req = Request::new(Body::empty());
+        let cookies = HeaderValue::from_bytes(b"a=1; b=\xfe\xff; s=secret").unwrap();
+        req.headers_mut().insert(header::COOKIE, cookies);
+
+        let md = set_cookie("c", TextModify::Set("3".to_owned()));
+        let req = md
+            .modify_req(req, &TextTypes::default(), DECODED_LIMIT)
+            .await;
+        let req = req.unwrap();
+        let cookies = req.headers()[header::COOKIE].as_bytes();
+        assert_eq!(cookies, b"a=1; b=\xfe\xff; s=secret; c=3");
+    }
+
+    #[tokio::test]
+    async fn cookie_newline() {
+        let res = response(&[("set-cookie", "a=1")], Body::empty());
+
+        let md = set_cookie("a", TextModify::Set("1\n2".to_owned()));
+        let res = modify_res(&md, res).await;
+        assert_eq!(res.headers()[header::SET_COOKIE], "a=1");
+        assert!(!res.headers().contains_key(header::COOKIE));
+    }
+
+    #[tokio::test]
+    async fn text_types() {
+        let md = replace("world", "mitm");
+        let json = || {
+            response(
+                &[("content-type", "Application/JSON")],
+                r#"{"hello":"world"}"#,
+            )
+        };
+
+        let res = modify_res(&md, json()).await;
+        assert_eq!(body_of(res).await, r#"{"hello":"world"}"#);
+
+        let types = TextTypes::new(vec!["JSON".to_owned()]);
+        let head = RequestHead::default();
+        let res = md.modify_res(&head, json(), &types, DECODED_LIMIT).await;
+        assert_eq!(body_of(res).await, r#"{"hello":"mitm"}"#);
+    }
+
+    /// A 100 MiB body of 1 MiB chunks, counting the chunks read from it.
+    fn large_body(read: Arc<AtomicUsize>) -> Body {
+        let chunks = futures_util::stream::iter(0..100).map(move |_| {
+            read.fetch_add(1, Ordering::SeqCst);
+            Ok::<_, hyper::Error>(Bytes::from(vec![b'a'; 1 << 20]))
+        });
+        Body::wrap_stream(chunks)
+    }
+
+    fn limited(modify: Modify) -> ConditionalModify {
+        ConditionalModify {
+            when: None,
+            when_body: None,
+            log_diff: None,
+            max_body: Some(1 << 20),
+            modify,
+        }
+    }
+
+    #[tokio::test]
+    async fn large_body_header() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let res = response(&[("content-type", "text/html")], large_body(read.clone()));
+
+        let md = limited(Modify::Header(MapModify {
+            key: "x-mitm".to_owned(),
+            value: Some(TextModify::Set("1".to_owned())),
+            ..Default::default()
+        }));
+        let res = md
+            .modify_res(&RequestHead::default(), res, &TextTypes::default())
+            .await;
+        assert_eq!(res.headers()["x-mitm"], "1");
+        assert_eq!(read.load(Ordering::SeqCst), 0);
+        assert_eq!(body_of(res).await.len(), 100 << 20);
+    }
+
+    #[tokio::test]
+    async fn large_body_over_limit() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let res = response(&[("content-type", "text/html")], large_body(read.clone()));
+
+        let md = limited(replace("a", "b"));
+        let res = md
+            .modify_res(&RequestHead::default(), res, &TextTypes::default())
+            .await;
+        assert_eq!(read.load(Ordering::SeqCst), 2);
+        let body = body_of(res).await;
+        assert_eq!(body.len(), 100 << 20);
+        assert!(body.iter().all(|b| *b == b'a'));
+    }
+
+    fn set_header(insert: bool) -> Modify {
+        Modify::Header(MapModify {
+            key: "x-frame-options".to_owned(),
+            value: Some(TextModify::Set("DENY".to_owned())),
+            insert,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn header_present() {
+        for insert in [true, false] {
+            let res = response(&[("x-frame-options", "SAMEORIGIN")], Body::empty());
+            let res = modify_res(&set_header(insert), res).await;
+            let values: Vec<_> = res.headers().get_all("x-frame-options").iter().collect();
+            assert_eq!(values, ["DENY"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn header_absent() {
+        let res = modify_res(&set_header(true), response(&[], Body::empty())).await;
+        assert_eq!(res.headers()["x-frame-options"], "DENY");
+
+        let res = modify_res(&set_header(false), response(&[], Body::empty())).await;
+        assert!(!res.headers().contains_key("x-frame-options"));
+    }
+
+    fn conditional(json: &str) -> Result<ConditionalModify, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn conditional_unknown_key() {
+        let md = conditional(r#"{"when": {"header": "x"}, "status": 200}"#);
+        assert!(md.unwrap().when.is_some());
+
+        let err = conditional(r#"{"wehn": {"header": "x"}, "status": 200}"#).unwrap_err();
+        assert!(err.to_string().contains("unknown field `wehn`"), "{}", err);
+
+        let err = conditional(r#"{"status": 200, "header": {"key": "x"}}"#).unwrap_err();
+        assert!(
+            err.to_string().contains("unknown field `header`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn multi_regex_in_order() {
+        let md: TextModify =
+            serde_json::from_str(r#"[["cat", "dog"], ["dog", "wolf"], ["(\\w+)s", "$1"]]"#)
+                .unwrap();
+        assert!(matches!(md, TextModify::MultiRegex(ref rules) if rules.len() == 3));
+        // each rule sees the output of the ones before it
+        assert_eq!(md.exec_action("cats and dogs"), "wolf and wolf");
+
+        let swapped: TextModify =
+            serde_json::from_str(r#"[["dog", "wolf"], ["cat", "dog"]]"#).unwrap();
+        assert_eq!(swapped.exec_action("cat and dog"), "dog and wolf");
+    }
+
+    #[test]
+    fn multi_regex_checked() {
+        let err = serde_json::from_str::<TextModify>(r#"[["a", "b"], ["(c)", "$2"]]"#).unwrap_err();
+        assert!(err.to_string().contains("refers to group $2"), "{}", err);
+    }
+}
@@ -0,0 +1,153 @@
+use http::HeaderValue;
+use hyper::{header, HeaderMap};
+use log::error;
+use lol_html::{html_content::Element, rewrite_str, ElementContentHandlers, RewriteStrSettings};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, cell::RefCell};
+
+use super::html::CssSelector;
+
+/// Emits `Link: <url>; rel=preload` headers for assets found in an html body.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Preload {
+    #[serde(default = "default_assets")]
+    pub assets: Vec<PreloadAsset>,
+    /// At most this many links are added.
+    #[serde(default = "default_max")]
+    pub max: usize,
+}
+
+/// Elements matching `selector` are preloaded from their `attr` attribute,
+/// with `as` telling the browser what kind of resource it is.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PreloadAsset {
+    pub selector: CssSelector,
+    pub attr: String,
+    #[serde(rename = "as")]
+    pub as_type: String,
+}
+
+fn default_assets() -> Vec<PreloadAsset> {
+    [
+        ("script[src]", "src", "script"),
+        (r#"link[rel="stylesheet"][href]"#, "href", "style"),
+    ]
+    .into_iter()
+    .map(|(selector, attr, as_type)| PreloadAsset {
+        // known to parse
+        selector: CssSelector::try_from(selector.to_owned()).unwrap(),
+        attr: attr.to_owned(),
+        as_type: as_type.to_owned(),
+    })
+    .collect()
+}
+
+fn default_max() -> usize {
+    8
+}
+
+impl Preload {
+    /// Scans `html` and appends the preload links after any existing `Link`
+    /// headers, skipping links they already have.
+    pub fn exec_action(&self, html: &str, headers: &mut HeaderMap) {
+        let links = RefCell::new(Vec::<String>::new());
+
+        let mut element_content_handlers = vec![];
+        for asset in &self.assets {
+            let links = &links;
+            element_content_handlers.push((
+                Cow::Borrowed(asset.selector.selector()),
+                ElementContentHandlers::default().element(move |el: &mut Element| {
+                    if let Some(url) = el.get_attribute(&asset.attr) {
+                        let link = format!("<{}>; rel=preload; as={}", url, asset.as_type);
+                        let mut links = links.borrow_mut();
+                        if !links.contains(&link) {
+                            links.push(link);
+                        }
+                    }
+                    Ok(())
+                }),
+            ));
+        }
+
+        if let Err(err) = rewrite_str(
+            html,
+            RewriteStrSettings {
+                element_content_handlers,
+                ..RewriteStrSettings::new()
+            },
+        ) {
+            error!("preload html parse failed: {}", err);
+            return;
+        }
+
+        // every existing `Link` header is kept, the new links go in one more
+        let existing: Vec<&str> = headers
+            .get_all(header::LINK)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        let links: Vec<String> = links
+            .into_inner()
+            .into_iter()
+            .filter(|link| !existing.iter().any(|v| v.contains(link.as_str())))
+            .take(self.max)
+            .collect();
+        if links.is_empty() {
+            return;
+        }
+
+        match HeaderValue::from_str(&links.join(", ")) {
+            Ok(value) => {
+                headers.append(header::LINK, value);
+            }
+            Err(err) => error!("preload link header invalid: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preload(json: serde_json::Value) -> Result<Preload, serde_json::Error> {
+        serde_json::from_value(json)
+    }
+
+    #[test]
+    fn links() {
+        let html = r#"<script src="/app.js"></script><link rel="stylesheet" href="/app.css"><script src="/app.js"></script>"#;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::LINK,
+            HeaderValue::from_static("</app.css>; rel=preload; as=style"),
+        );
+        preload(serde_json::json!({}))
+            .unwrap()
+            .exec_action(html, &mut headers);
+
+        let links: Vec<_> = headers.get_all(header::LINK).iter().collect();
+        assert_eq!(
+            links,
+            [
+                "</app.css>; rel=preload; as=style",
+                "</app.js>; rel=preload; as=script"
+            ]
+        );
+    }
+
+    #[test]
+    fn selector_invalid() {
+        let err = preload(serde_json::json!({
+            "assets": [{"selector": "img[", "attr": "src", "as": "image"}],
+        }))
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("css selector img[ invalid"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use http::{header::HeaderName, HeaderValue};
+use hyper::{header, Body, HeaderMap, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Simulates a rate limited endpoint with a token bucket holding `limit`
+/// requests, refilled evenly so an empty bucket is full again after `window`
+/// seconds.
+///
+/// Each request takes a token. When none is left the request is answered
+/// with `429 Too Many Requests` and a `Retry-After` for the next token,
+/// without reaching upstream. The bucket lives in memory, starts full and is
+/// shared by every clone of the rule.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RateLimit {
+    pub limit: u64,
+    #[serde(default = "default_window")]
+    pub window: u64,
+    /// Add `X-RateLimit-*` headers to the responses let through as well.
+    #[serde(default = "default_headers")]
+    pub headers: bool,
+    #[serde(default)]
+    pub body: Option<String>,
+
+    #[serde(skip)]
+    bucket: Arc<Mutex<Option<Bucket>>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The state of the bucket after a request, as told to the client.
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    limit: u64,
+    remaining: u64,
+    /// Seconds until the bucket is full.
+    reset: u64,
+}
+
+/// A request turned away as the bucket was empty.
+#[derive(Debug, Clone, Copy)]
+pub struct Limited {
+    quota: Quota,
+    /// Seconds until the next token.
+    retry_after: u64,
+}
+
+fn default_window() -> u64 {
+    60
+}
+
+fn default_headers() -> bool {
+    true
+}
+
+impl RateLimit {
+    /// Takes a token, returning the quota left, or when the bucket is empty
+    /// what [`RateLimit::build_res`] needs for the `429` response.
+    pub fn take(&self) -> Result<Quota, Limited> {
+        let limit = self.limit as f64;
+        // tokens per second
+        let rate = limit / self.window.max(1) as f64;
+
+        let (tokens, taken) = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let bucket = bucket.get_or_insert(Bucket {
+                tokens: limit,
+                updated: now,
+            });
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(limit);
+            bucket.updated = now;
+
+            let taken = bucket.tokens >= 1.0;
+            if taken {
+                bucket.tokens -= 1.0;
+            }
+            (bucket.tokens, taken)
+        };
+
+        let quota = Quota {
+            limit: self.limit,
+            remaining: tokens.floor() as u64,
+            reset: seconds((limit - tokens) / rate),
+        };
+        if taken {
+            return Ok(quota);
+        }
+        Err(Limited {
+            quota,
+            retry_after: seconds((1.0 - tokens) / rate).max(1),
+        })
+    }
+
+    /// The `429 Too Many Requests` response for a request that was limited.
+    pub fn build_res(&self, limited: Limited) -> Response<Body> {
+        let body = self.body.clone().unwrap_or_default();
+        let mut res = Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap();
+        res.headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(limited.retry_after));
+        limited.quota.set_headers(res.headers_mut());
+        res
+    }
+}
+
+impl Quota {
+    pub fn set_headers(&self, headers: &mut HeaderMap) {
+        for (name, value) in [
+            ("x-ratelimit-limit", self.limit),
+            ("x-ratelimit-remaining", self.remaining),
+            ("x-ratelimit-reset", self.reset),
+        ] {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+        }
+    }
+}
+
+/// Whole seconds, rounded up so clients never retry too early.
+fn seconds(secs: f64) -> u64 {
+    if secs.is_finite() && secs > 0.0 {
+        secs.ceil() as u64
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn rate_limit(limit: u64, window: u64) -> RateLimit {
+        serde_json::from_value(serde_json::json!({ "limit": limit, "window": window })).unwrap()
+    }
+
+    /// Moves the last refill of the bucket `secs` seconds back.
+    fn rewind(rate_limit: &RateLimit, secs: u64) {
+        let mut bucket = rate_limit.bucket.lock().unwrap();
+        let bucket = bucket.as_mut().unwrap();
+        bucket.updated -= Duration::from_secs(secs);
+    }
+
+    #[test]
+    fn empties() {
+        let rate_limit = rate_limit(2, 10);
+        let quota = rate_limit.take().unwrap();
+        assert_eq!((quota.limit, quota.remaining, quota.reset), (2, 1, 5));
+        assert_eq!(rate_limit.take().unwrap().remaining, 0);
+
+        let limited = rate_limit.take().unwrap_err();
+        assert_eq!(limited.retry_after, 5);
+        assert_eq!(limited.quota.reset, 10);
+
+        let res = rate_limit.build_res(limited);
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "5");
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "0");
+    }
+
+    #[test]
+    fn refills() {
+        let rate_limit = rate_limit(2, 10);
+        rate_limit.take().unwrap();
+        rate_limit.take().unwrap();
+        assert!(rate_limit.take().is_err());
+
+        // one token back every 5 seconds
+        rewind(&rate_limit, 5);
+        assert_eq!(rate_limit.take().unwrap().remaining, 0);
+        assert!(rate_limit.take().is_err());
+
+        // never more than the limit
+        rewind(&rate_limit, 60);
+        assert_eq!(rate_limit.take().unwrap().remaining, 1);
+        assert_eq!(rate_limit.take().unwrap().remaining, 0);
+        assert!(rate_limit.take().is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use http::{header::HeaderName, HeaderValue};
+use hyper::{header, Body, HeaderMap, Request, Response, StatusCode};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, str::FromStr};
+
+/// A synthetic response returned without forwarding the request upstream.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Respond {
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+    #[serde(default)]
+    pub grpc_web: Option<GrpcWeb>,
+    /// Set a strong `ETag` computed from the body and answer a matching
+    /// `If-None-Match` with `304 Not Modified`.
+    #[serde(default)]
+    pub etag: bool,
+    #[serde(default)]
+    pub cache_control: Option<String>,
+}
+
+/// Frames the body as a single gRPC-Web message followed by a trailers frame.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GrpcWeb {
+    /// Use `application/grpc-web-text`, base64 encoding the whole payload.
+    #[serde(default)]
+    pub text: bool,
+    /// The body is base64 of the binary protobuf message instead of raw text.
+    #[serde(default)]
+    pub base64: bool,
+    #[serde(default)]
+    pub grpc_status: u32,
+    #[serde(default)]
+    pub grpc_message: Option<String>,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+const GRPC_DATA_FRAME: u8 = 0x00;
+const GRPC_TRAILER_FRAME: u8 = 0x80;
+
+impl Respond {
+    pub fn build_res(&self, req: &Request<Body>) -> Response<Body> {
+        let status = StatusCode::from_u16(self.status).unwrap_or_else(|err| {
+            error!("respond status {} invalid: {}", self.status, err);
+            StatusCode::OK
+        });
+
+        let (body, content_type) = match self.grpc_web {
+            Some(ref grpc) => (grpc.frame(self.body.as_bytes()), Some(grpc.content_type())),
+            None => (self.body.clone().into_bytes(), None),
+        };
+
+        let mut res = Response::builder()
+            .status(status)
+            .body(Body::default())
+            .unwrap();
+        let headers = res.headers_mut();
+        if let Some(content_type) = content_type {
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        }
+        for (key, value) in &self.headers {
+            match (HeaderName::from_str(key), HeaderValue::from_str(value)) {
+                (Ok(key), Ok(value)) => {
+                    headers.insert(key, value);
+                }
+                _ => error!("respond header invalid: {}: {}", key, value),
+            }
+        }
+        if !body.is_empty() && !headers.contains_key(header::CONTENT_TYPE) {
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(guess_type(&body)),
+            );
+        }
+        if let Some(ref cache_control) = self.cache_control {
+            match HeaderValue::from_str(cache_control) {
+                Ok(value) => {
+                    headers.insert(header::CACHE_CONTROL, value);
+                }
+                Err(_) => error!("respond cache-control invalid: {}", cache_control),
+            }
+        }
+
+        if self.etag {
+            let etag = format!("\"{:016x}\"", fnv1a(&body));
+            let not_modified = if_none_match(req.headers(), &etag);
+            headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+            if not_modified {
+                headers.remove(header::CONTENT_LENGTH);
+                *res.status_mut() = StatusCode::NOT_MODIFIED;
+                return res;
+            }
+        }
+
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+        *res.body_mut() = Body::from(body);
+        res
+    }
+}
+
+impl GrpcWeb {
+    fn content_type(&self) -> &'static str {
+        if self.text {
+            "application/grpc-web-text+proto"
+        } else {
+            "application/grpc-web+proto"
+        }
+    }
+
+    fn frame(&self, message: &[u8]) -> Vec<u8> {
+        let message = if self.base64 {
+            base64::decode(message).unwrap_or_else(|err| {
+                error!("grpc-web message is not valid base64: {}", err);
+                message.to_vec()
+            })
+        } else {
+            message.to_vec()
+        };
+
+        let mut trailers = format!("grpc-status:{}\r\n", self.grpc_status);
+        if let Some(ref grpc_message) = self.grpc_message {
+            trailers.push_str(&format!(
+                "grpc-message:{}\r\n",
+                percent_encode(grpc_message)
+            ));
+        }
+
+        let mut payload = Vec::with_capacity(message.len() + trailers.len() + 10);
+        push_frame(&mut payload, GRPC_DATA_FRAME, &message);
+        push_frame(&mut payload, GRPC_TRAILER_FRAME, trailers.as_bytes());
+
+        if self.text {
+            base64::encode(payload).into_bytes()
+        } else {
+            payload
+        }
+    }
+}
+
+/// The `Content-Type` of a body sent without one: JSON when it parses as an
+/// object or array, plain text otherwise.
+fn guess_type(body: &[u8]) -> &'static str {
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(_) | serde_json::Value::Array(_)) => "application/json",
+        _ => "text/plain; charset=utf-8",
+    }
+}
+
+/// Whether `If-None-Match` lists `etag`, using the weak comparison RFC 7232
+/// requires for this header.
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// FNV-1a, stable across runs and builds so etags survive a restart.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn push_frame(buf: &mut Vec<u8>, flag: u8, data: &[u8]) {
+    buf.push(flag);
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// `grpc-message` is percent-encoded as required by the gRPC HTTP/2 spec.
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for b in text.bytes() {
+        if (0x20..=0x7e).contains(&b) && b != b'%' {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
@@ -0,0 +1,48 @@
+use fancy_regex::escape;
+use http::{header::HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::get_regex;
+
+/// Rewrites the scheme of absolute urls pointing at `host`, leaving links to
+/// any other host alone.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SchemeRewrite {
+    pub host: String,
+    pub to: Scheme,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+impl SchemeRewrite {
+    pub fn exec_action(&self, text: &str) -> String {
+        // also matches json escaped slashes, and stops at the end of the host
+        // so `example.com` doesn't touch `example.com.evil.net`
+        let re = format!(r"(?i)\bhttps?:(//|\\/\\/){}(?![\w.-])", escape(&self.host));
+        let scheme = match self.to {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        };
+        get_regex(&re)
+            .replace_all(text, format!("{}:${{1}}{}", scheme, self.host).as_str())
+            .to_string()
+    }
+
+    pub fn modify_headers(&self, headers: &mut HeaderMap, names: &[HeaderName]) {
+        for name in names {
+            if let Some(value) = headers.get_mut(name) {
+                let new_value = self.exec_action(value.to_str().unwrap_or_default());
+                if let Ok(new_value) = HeaderValue::from_str(&new_value) {
+                    *value = new_value;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use hyper::{Body, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt};
+
+use super::{
+    modify::{ConditionalModify, TextTypes},
+    when::RequestHead,
+};
+
+/// Runs the response modifies listed for the status of the response, in
+/// order.
+///
+/// Keys are an exact code such as `404`, a class such as `4xx`, or `default`.
+/// An exact code wins over its class, which wins over `default`. Statuses
+/// without any entry are left alone. Keys are checked when the rule is
+/// loaded.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OnStatus(HashMap<StatusKey, Vec<ConditionalModify>>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum StatusKey {
+    Code(u16),
+    /// The first digit of the codes in the class.
+    Class(u16),
+    Default,
+}
+
+impl TryFrom<String> for StatusKey {
+    type Error = String;
+
+    fn try_from(key: String) -> Result<Self, Self::Error> {
+        let invalid = || {
+            format!(
+                "invalid status key {:?}, expected e.g. 404, 4xx or default",
+                key
+            )
+        };
+        if key == "default" {
+            return Ok(StatusKey::Default);
+        }
+        if key.len() != 3 || !key.is_ascii() {
+            return Err(invalid());
+        }
+        let class = match key[..1].parse::<u16>() {
+            Ok(class @ 1..=5) => class,
+            _ => return Err(invalid()),
+        };
+        if key[1..].eq_ignore_ascii_case("xx") {
+            return Ok(StatusKey::Class(class));
+        }
+        match key.parse::<u16>() {
+            Ok(code) if key.bytes().all(|b| b.is_ascii_digit()) => Ok(StatusKey::Code(code)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl From<StatusKey> for String {
+    fn from(key: StatusKey) -> Self {
+        key.to_string()
+    }
+}
+
+impl fmt::Display for StatusKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatusKey::Code(code) => write!(f, "{}", code),
+            StatusKey::Class(class) => write!(f, "{}xx", class),
+            StatusKey::Default => write!(f, "default"),
+        }
+    }
+}
+
+impl OnStatus {
+    fn select(&self, status: StatusCode) -> Option<&Vec<ConditionalModify>> {
+        let code = status.as_u16();
+        self.0
+            .get(&StatusKey::Code(code))
+            .or_else(|| self.0.get(&StatusKey::Class(code / 100)))
+            .or_else(|| self.0.get(&StatusKey::Default))
+    }
+
+    pub async fn modify_res(
+        &self,
+        head: &RequestHead,
+        res: Response<Body>,
+        types: &TextTypes,
+    ) -> Response<Body> {
+        let modifies = match self.select(res.status()) {
+            Some(modifies) => modifies,
+            None => return res,
+        };
+        let mut res = res;
+        for modify in modifies {
+            res = modify.modify_res(head, res, types).await;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: &str) -> Result<StatusKey, String> {
+        StatusKey::try_from(key.to_owned())
+    }
+
+    #[test]
+    fn status_key() {
+        assert_eq!(key("404"), Ok(StatusKey::Code(404)));
+        assert_eq!(key("4XX"), Ok(StatusKey::Class(4)));
+        assert_eq!(key("default"), Ok(StatusKey::Default));
+        for invalid in ["600", "4x4", "40", "+04", "é1", "1é", "é"] {
+            assert!(key(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn status_key_non_ascii() {
+        let err = serde_json::from_str::<OnStatus>(r#"{"é1": []}"#).unwrap_err();
+        assert!(err.to_string().contains("invalid status key"), "{}", err);
+    }
+}
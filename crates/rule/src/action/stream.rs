@@ -0,0 +1,188 @@
+use fancy_regex::Regex;
+use futures_util::stream;
+use hyper::{
+    body::{Bytes, HttpBody},
+    Body,
+};
+
+/// Replaces every match of `re` in `body` without buffering it whole.
+///
+/// Text is held back in a sliding buffer of up to twice `window` bytes. Only
+/// matches starting before the last `window` bytes are replaced when the
+/// buffer is flushed, later ones wait for more data, so any match up to
+/// `window` bytes long is seen in full. Longer matches may be missed or cut
+/// short, and `^` or look-behinds may also match at a flush boundary.
+///
+/// Bodies that turn out not to be UTF-8 are passed on unchanged from that
+/// point.
+pub(crate) fn replace_all(body: Body, re: Regex, new: String, window: usize) -> Body {
+    let state = StreamReplace {
+        body,
+        re,
+        new,
+        window: window.max(1),
+        buf: String::new(),
+        pending: vec![],
+        passthrough: false,
+        done: false,
+    };
+
+    Body::wrap_stream(stream::unfold(state, |mut state| async move {
+        let out = state.next().await?;
+        Some((out, state))
+    }))
+}
+
+struct StreamReplace {
+    body: Body,
+    re: Regex,
+    new: String,
+    window: usize,
+    /// Decoded text not sent on yet.
+    buf: String,
+    /// Trailing bytes of an incomplete UTF-8 sequence.
+    pending: Vec<u8>,
+    passthrough: bool,
+    done: bool,
+}
+
+impl StreamReplace {
+    async fn next(&mut self) -> Option<hyper::Result<Bytes>> {
+        loop {
+            if self.done {
+                return None;
+            }
+
+            let chunk = match self.body.data().await {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(err)) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+                None => {
+                    self.done = true;
+                    let mut out = self.flush(true).into_bytes();
+                    out.append(&mut self.pending);
+                    if out.is_empty() {
+                        return None;
+                    }
+                    return Some(Ok(out.into()));
+                }
+            };
+
+            if self.passthrough {
+                return Some(Ok(chunk));
+            }
+            self.pending.extend_from_slice(&chunk);
+            let valid = match std::str::from_utf8(&self.pending) {
+                Ok(text) => text.len(),
+                // an incomplete sequence at the end may finish in the next chunk
+                Err(err) if err.error_len().is_none() => err.valid_up_to(),
+                Err(_) => {
+                    self.passthrough = true;
+                    let mut out = std::mem::take(&mut self.buf).into_bytes();
+                    out.append(&mut self.pending);
+                    return Some(Ok(out.into()));
+                }
+            };
+            let rest = self.pending.split_off(valid);
+            // checked above
+            self.buf
+                .push_str(std::str::from_utf8(&self.pending).unwrap_or_default());
+            self.pending = rest;
+
+            if self.buf.len() >= self.window * 2 {
+                let out = self.flush(false);
+                if !out.is_empty() {
+                    return Some(Ok(out.into()));
+                }
+            }
+        }
+    }
+
+    /// Replaces matches starting before the last `window` bytes, or all of
+    /// them at the end of the body, and returns the text that is done.
+    fn flush(&mut self, end: bool) -> String {
+        let mut cut = if end {
+            self.buf.len()
+        } else {
+            self.buf.len() - self.window
+        };
+        while !self.buf.is_char_boundary(cut) {
+            cut -= 1;
+        }
+
+        let mut out = String::with_capacity(self.buf.len());
+        let mut last = 0;
+        for caps in self.re.captures_iter(&self.buf) {
+            let caps = match caps {
+                Ok(caps) => caps,
+                Err(_) => break,
+            };
+            let whole = caps.get(0).unwrap();
+            if !end && whole.start() >= cut {
+                break;
+            }
+            out.push_str(&self.buf[last..whole.start()]);
+            caps.expand(&self.new, &mut out);
+            last = whole.end();
+        }
+
+        let keep = last.max(cut);
+        out.push_str(&self.buf[last..keep]);
+        self.buf.drain(..keep);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+    use hyper::body::to_bytes;
+
+    fn chunked(chunks: &[&'static [u8]]) -> Body {
+        let chunks: Vec<hyper::Result<Bytes>> =
+            chunks.iter().map(|c| Ok(Bytes::from_static(c))).collect();
+        Body::wrap_stream(stream::iter(chunks))
+    }
+
+    async fn replaced(chunks: &[&'static [u8]], re: &str, new: &str, window: usize) -> Bytes {
+        let body = replace_all(
+            chunked(chunks),
+            Regex::new(re).unwrap(),
+            new.to_owned(),
+            window,
+        );
+        to_bytes(body).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn across_chunks() {
+        let chunks: &[&[u8]] = &[b"aaa fo", b"o b", b"ar foo", b"bar aaa"];
+        let out = replaced(chunks, "foo ?bar", "x", 8).await;
+        assert_eq!(out, "aaa x x aaa");
+
+        // one byte at a time
+        let text = b"price: 10 USD, price: 20 USD";
+        let chunks: Vec<&'static [u8]> = (0..text.len()).map(|i| &text[i..i + 1]).collect();
+        let out = replaced(&chunks, r"(\d+) USD", "$1 EUR", 8).await;
+        assert_eq!(out, "price: 10 EUR, price: 20 EUR");
+    }
+
+    #[tokio::test]
+    async fn split_char() {
+        // "café café" with both é split between chunks
+        let chunks: &[&[u8]] = &[b"caf\xc3", b"\xa9 caf\xc3", b"\xa9"];
+        let out = replaced(chunks, "café", "tea", 8).await;
+        assert_eq!(out, "tea tea");
+    }
+
+    #[tokio::test]
+    async fn not_utf8() {
+        let chunks: &[&[u8]] = &[b"foo", b"\xff foo", b" foo"];
+        let out = replaced(chunks, "foo", "bar", 1).await;
+        // replaced up to the first chunk that isn't UTF-8
+        assert_eq!(out, &b"bar\xff foo foo"[..]);
+    }
+}
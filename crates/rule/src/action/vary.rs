@@ -0,0 +1,55 @@
+use http::HeaderValue;
+use hyper::{header, HeaderMap};
+use serde::{Deserialize, Serialize};
+
+/// Brings `Vary` in line with what the proxy actually serves, e.g. dropping
+/// `Accept-Encoding` once the encoding is forced.
+///
+/// `remove` is applied before `add`, names are compared case-insensitively
+/// and `*` in `remove` drops the whole header. The header is removed when no
+/// name is left.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VaryRewrite {
+    #[serde(default)]
+    pub remove: Vec<String>,
+    #[serde(default)]
+    pub add: Vec<String>,
+}
+
+impl VaryRewrite {
+    pub fn modify_headers(&self, headers: &mut HeaderMap) {
+        let mut names: Vec<String> = headers
+            .get_all(header::VARY)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|name| name.trim().to_owned())
+            .filter(|name| !name.is_empty())
+            .collect();
+
+        if self.remove.iter().any(|name| name.trim() == "*") {
+            names.clear();
+        }
+        names.retain(|name| {
+            !self
+                .remove
+                .iter()
+                .any(|r| r.trim().eq_ignore_ascii_case(name))
+        });
+        for name in &self.add {
+            let name = name.trim();
+            if !name.is_empty() && !names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+                names.push(name.to_owned());
+            }
+        }
+
+        headers.remove(header::VARY);
+        if names.is_empty() {
+            return;
+        }
+        if let Ok(value) = HeaderValue::from_str(&names.join(", ")) {
+            headers.insert(header::VARY, value);
+        }
+    }
+}
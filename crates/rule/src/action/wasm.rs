@@ -0,0 +1,225 @@
+use anyhow::{anyhow, Result};
+use http::{header::HeaderName, HeaderValue};
+use hyper::{body::Bytes, HeaderMap};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::{str::FromStr, sync::Arc};
+use tokio::task;
+use wasmtime::{Config, Engine, Instance, Module, Store};
+
+/// Rewrites the body with a function exported by a WASM module.
+///
+/// The module is compiled when the rule is loaded. A module that fails to
+/// load is logged and the modify then leaves bodies alone. Each call runs in
+/// a fresh instance without any imports, stopped once it used `fuel` units.
+///
+/// ABI v1, all integers little-endian `u32`, both directions sharing the
+/// layout `count, (name len, name, value len, value) * count, body len, body`:
+///
+/// - the module exports `memory` and `alloc(len: i32) -> i32`, which returns
+///   a buffer the input is written to;
+/// - `func(ptr: i32, len: i32) -> i64` gets the request or response headers
+///   listed in `headers` and the body, and returns the output buffer as
+///   `ptr << 32 | len`, or a negative number to keep everything unchanged;
+/// - the output headers are set, replacing existing ones, and its body
+///   replaces the body, `Content-Length` is updated afterwards.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(from = "WasmConfig", into = "WasmConfig")]
+pub struct WasmModify {
+    config: WasmConfig,
+    loaded: Option<Arc<Loaded>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WasmConfig {
+    pub module: String,
+    #[serde(default = "default_func")]
+    pub func: String,
+    #[serde(default)]
+    pub headers: Vec<String>,
+    #[serde(default = "default_fuel")]
+    pub fuel: u64,
+}
+
+struct Loaded {
+    engine: Engine,
+    module: Module,
+}
+
+impl std::fmt::Debug for Loaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Loaded").finish_non_exhaustive()
+    }
+}
+
+fn default_func() -> String {
+    "transform".to_owned()
+}
+
+fn default_fuel() -> u64 {
+    100_000_000
+}
+
+impl From<WasmConfig> for WasmModify {
+    fn from(config: WasmConfig) -> Self {
+        let loaded = match load(&config.module) {
+            Ok(loaded) => {
+                info!("wasm module {} loaded", config.module);
+                Some(Arc::new(loaded))
+            }
+            Err(err) => {
+                error!("wasm module {} not loaded: {}", config.module, err);
+                None
+            }
+        };
+        Self { config, loaded }
+    }
+}
+
+impl From<WasmModify> for WasmConfig {
+    fn from(modify: WasmModify) -> Self {
+        modify.config
+    }
+}
+
+fn load(path: &str) -> Result<Loaded> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config)?;
+    let module = Module::from_file(&engine, path)?;
+    Ok(Loaded { engine, module })
+}
+
+impl WasmModify {
+    /// Transforms the body and headers in place, leaving them as they were
+    /// when the module is missing, fails or declines.
+    ///
+    /// The module runs on tokio's blocking pool, as `fuel` bounds the work it
+    /// does but not how long that takes.
+    pub async fn exec_action(&self, headers: &mut HeaderMap, body: Bytes) -> Bytes {
+        let loaded = match self.loaded {
+            Some(ref loaded) => loaded.clone(),
+            None => return body,
+        };
+        let called = {
+            let modify = self.clone();
+            let headers = headers.clone();
+            let body = body.clone();
+            task::spawn_blocking(move || modify.call(&loaded, &headers, &body))
+                .await
+                .unwrap_or_else(|err| Err(err.into()))
+        };
+        match called {
+            Ok(Some((new_headers, new_body))) => {
+                for (name, value) in new_headers {
+                    match (HeaderName::from_str(&name), HeaderValue::from_str(&value)) {
+                        (Ok(name), Ok(value)) => {
+                            headers.insert(name, value);
+                        }
+                        _ => error!("wasm header invalid: {}: {}", name, value),
+                    }
+                }
+                new_body.into()
+            }
+            Ok(None) => body,
+            Err(err) => {
+                error!(
+                    "wasm {} {} failed: {}",
+                    self.config.module, self.config.func, err
+                );
+                body
+            }
+        }
+    }
+
+    fn call(&self, loaded: &Loaded, headers: &HeaderMap, body: &[u8]) -> Result<Option<Output>> {
+        let selected: Vec<(String, String)> = self
+            .config
+            .headers
+            .iter()
+            .filter_map(|name| {
+                let value = headers.get(name.as_str())?.to_str().ok()?;
+                Some((name.to_lowercase(), value.to_owned()))
+            })
+            .collect();
+        let input = encode(&selected, body);
+
+        let mut store = Store::new(&loaded.engine, ());
+        store.add_fuel(self.config.fuel)?;
+        let instance = Instance::new(&mut store, &loaded.module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("no exported memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let func = instance.get_typed_func::<(i32, i32), i64>(&mut store, &self.config.func)?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, &input)?;
+        let ret = func.call(&mut store, (ptr, len))?;
+        if ret < 0 {
+            return Ok(None);
+        }
+
+        let (out_ptr, out_len) = ((ret >> 32) as u32 as usize, ret as u32 as usize);
+        // checked before allocating, the length comes from the module
+        match out_ptr.checked_add(out_len) {
+            Some(end) if end <= memory.data_size(&store) => {}
+            _ => return Err(anyhow!("output out of bounds")),
+        }
+        let mut output = vec![0; out_len];
+        memory.read(&store, out_ptr, &mut output)?;
+        decode(&output).map(Some)
+    }
+}
+
+type Output = (Vec<(String, String)>, Vec<u8>);
+
+fn encode(headers: &[(String, String)], body: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    buf.extend_from_slice(&(headers.len() as u32).to_le_bytes());
+    for (name, value) in headers {
+        push_bytes(&mut buf, name.as_bytes());
+        push_bytes(&mut buf, value.as_bytes());
+    }
+    push_bytes(&mut buf, body);
+    buf
+}
+
+fn push_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn decode(buf: &[u8]) -> Result<Output> {
+    let mut rest = buf;
+    let count = read_u32(&mut rest)?;
+    let mut headers = vec![];
+    for _ in 0..count {
+        let name = String::from_utf8(read_bytes(&mut rest)?.to_vec())?;
+        let value = String::from_utf8(read_bytes(&mut rest)?.to_vec())?;
+        headers.push((name, value));
+    }
+    let body = read_bytes(&mut rest)?.to_vec();
+    Ok((headers, body))
+}
+
+fn read_u32(rest: &mut &[u8]) -> Result<u32> {
+    if rest.len() < 4 {
+        return Err(anyhow!("output truncated"));
+    }
+    let (n, tail) = (*rest).split_at(4);
+    *rest = tail;
+    Ok(u32::from_le_bytes([n[0], n[1], n[2], n[3]]))
+}
+
+fn read_bytes<'a>(rest: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = read_u32(rest)? as usize;
+    if rest.len() < len {
+        return Err(anyhow!("output truncated"));
+    }
+    let (bytes, tail) = (*rest).split_at(len);
+    *rest = tail;
+    Ok(bytes)
+}
@@ -0,0 +1,104 @@
+use http::HeaderValue;
+use hyper::{header, Body, HeaderMap, Request, Response, StatusCode};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use super::modify::TextModify;
+
+/// Rewrites `Sec-WebSocket-Protocol` in the upgrade handshake.
+///
+/// `request` modifies the list of subprotocols offered to upstream,
+/// `response` modifies the subprotocol accepted back to the client.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WebSocketProtocol {
+    #[serde(default)]
+    pub request: Option<TextModify>,
+    #[serde(default)]
+    pub response: Option<TextModify>,
+}
+
+impl WebSocketProtocol {
+    /// Rewrites the offered subprotocols, returning what the client offered
+    /// originally so the accepted one can be checked against it later.
+    pub fn modify_req(&self, req: &mut Request<Body>) -> Option<Vec<String>> {
+        if !is_websocket_upgrade(req.headers()) {
+            return None;
+        }
+        let offered = protocol_list(req.headers());
+
+        if let Some(ref md) = self.request {
+            let new_protocols = md.exec_action(&offered.join(", "));
+            let valid = new_protocols.split(',').all(|p| is_token(p.trim()));
+            match HeaderValue::from_str(&new_protocols) {
+                Ok(value) if valid => {
+                    info!("[WebSocketProtocol] offer {}", new_protocols);
+                    req.headers_mut()
+                        .insert(header::SEC_WEBSOCKET_PROTOCOL, value);
+                }
+                _ => error!("websocket protocol invalid: {}", new_protocols),
+            }
+        }
+
+        Some(offered)
+    }
+
+    pub fn modify_res(&self, offered: &[String], res: &mut Response<Body>) {
+        if res.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return;
+        }
+        let md = match self.response {
+            Some(ref md) => md,
+            None => return,
+        };
+
+        let accepted = res
+            .headers()
+            .get(header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        let new_protocol = md.exec_action(&accepted);
+        let new_protocol = new_protocol.trim();
+
+        // the client fails the handshake unless it offered the accepted protocol
+        if !is_token(new_protocol) || !offered.iter().any(|p| p == new_protocol) {
+            error!(
+                "websocket protocol {} was not offered by client: {:?}",
+                new_protocol, offered
+            );
+            return;
+        }
+        if let Ok(value) = HeaderValue::from_str(new_protocol) {
+            info!("[WebSocketProtocol] accept {}", new_protocol);
+            res.headers_mut()
+                .insert(header::SEC_WEBSOCKET_PROTOCOL, value);
+        }
+    }
+}
+
+fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false)
+}
+
+fn protocol_list(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|p| p.trim().to_owned())
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+/// RFC 7230 token, which is what RFC 6455 requires for subprotocol names.
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
@@ -0,0 +1,283 @@
+use cookie::Cookie;
+use http::{uri, Method, Uri, Version};
+use hyper::{body::to_bytes, header, Body, HeaderMap, Request};
+use mitm_core::mitm::TlsInfo;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{json::JsonPath, modify::within_limit, scheme::Scheme};
+use crate::cache::get_regex;
+
+/// A predicate that gates whether a modify runs at all.
+///
+/// Headers are those of the request or response being modified, the
+/// [`RequestHead`] is always the request's, also when modifying a response.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum When {
+    /// Matches when any of the predicates matches.
+    Any {
+        any: Vec<When>,
+    },
+    /// Matches when all of the predicates match.
+    All {
+        all: Vec<When>,
+    },
+    /// Matches when the predicate doesn't.
+    Not {
+        not: Box<When>,
+    },
+    /// Matches the scheme of the request uri.
+    Scheme {
+        scheme: Scheme,
+    },
+    /// Matches a regex against the reconstructed request line, see
+    /// [`RequestHead::request_line`].
+    RequestLine {
+        #[serde(rename = "request-line")]
+        request_line: String,
+    },
+    /// Matches the certificate the client presented over TLS.
+    ClientCert {
+        #[serde(rename = "client-cert")]
+        client_cert: ClientCertWhen,
+    },
+    Header(HeaderWhen),
+    Cookie(CookieWhen),
+}
+
+/// Without `re` the header only needs to be present, with `absent` it must
+/// be missing instead.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HeaderWhen {
+    pub header: String,
+    #[serde(default)]
+    pub re: Option<String>,
+    #[serde(default)]
+    pub absent: bool,
+}
+
+/// Like [`HeaderWhen`] for a cookie of the request, also when modifying the
+/// response.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CookieWhen {
+    pub cookie: String,
+    #[serde(default)]
+    pub re: Option<String>,
+    #[serde(default)]
+    pub absent: bool,
+}
+
+/// With `present` false the client must not have presented a certificate,
+/// otherwise it must have, with `subject` and `issuer` matching if set.
+///
+/// Never matches requests whose TLS isn't terminated by the proxy, such as
+/// plain HTTP, as there is no handshake to look at.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ClientCertWhen {
+    #[serde(default = "default_present")]
+    pub present: bool,
+    #[serde(default)]
+    pub subject: Option<String>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+}
+
+fn default_present() -> bool {
+    true
+}
+
+/// The parts of a request `when` predicates can look at, kept by the rule so
+/// they are still available for the response.
+#[derive(Debug, Clone, Default)]
+pub struct RequestHead {
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+    /// Set when the request came over TLS terminated by the proxy.
+    pub tls: Option<TlsInfo>,
+    /// The `Cookie` headers of the request, joined with `; `.
+    pub cookie: Option<String>,
+}
+
+impl RequestHead {
+    pub fn of(req: &Request<Body>) -> Self {
+        Self {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            version: req.version(),
+            tls: req.extensions().get::<TlsInfo>().cloned(),
+            cookie: cookie_header(req.headers()),
+        }
+    }
+
+    /// The request line as it would be sent over HTTP/1.1, e.g.
+    /// `GET /path?x=1 HTTP/1.1`: the method, the origin-form path and query
+    /// and the version, separated by single spaces. The host is not part of
+    /// it, and HTTP/2 requests show as `HTTP/2.0`.
+    pub fn request_line(&self) -> String {
+        let target = self
+            .uri
+            .path_and_query()
+            .map(|pq| pq.as_str())
+            .unwrap_or("/");
+        format!("{} {} {:?}", self.method, target, self.version)
+    }
+}
+
+fn cookie_header(headers: &HeaderMap) -> Option<String> {
+    let cookies: Vec<&str> = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    (!cookies.is_empty()).then(|| cookies.join("; "))
+}
+
+impl When {
+    pub fn is_match(&self, headers: &HeaderMap, head: &RequestHead) -> bool {
+        let uri = &head.uri;
+        match self {
+            When::Any { any } => any.iter().any(|w| w.is_match(headers, head)),
+            When::All { all } => all.iter().all(|w| w.is_match(headers, head)),
+            When::Not { not } => !not.is_match(headers, head),
+            When::Scheme { scheme } => {
+                let expected = match scheme {
+                    Scheme::Http => uri::Scheme::HTTP,
+                    Scheme::Https => uri::Scheme::HTTPS,
+                };
+                uri.scheme() == Some(&expected)
+            }
+            When::RequestLine { request_line } => get_regex(request_line)
+                .is_match(&head.request_line())
+                .unwrap_or(false),
+            When::ClientCert { client_cert } => client_cert.is_match(head.tls.as_ref()),
+            When::Header(w) => w.is_match(headers),
+            When::Cookie(w) => w.is_match(head.cookie.as_deref()),
+        }
+    }
+}
+
+impl ClientCertWhen {
+    fn is_match(&self, tls: Option<&TlsInfo>) -> bool {
+        let cert = match tls {
+            Some(tls) => tls.client_cert.as_ref(),
+            None => return false,
+        };
+        let cert = match (cert, self.present) {
+            (Some(cert), true) => cert,
+            (None, false) => return true,
+            _ => return false,
+        };
+        let matches = |re: &Option<String>, name: &str| match re {
+            Some(re) => get_regex(re).is_match(name).unwrap_or(false),
+            None => true,
+        };
+        matches(&self.subject, &cert.subject) && matches(&self.issuer, &cert.issuer)
+    }
+}
+
+impl HeaderWhen {
+    fn is_match(&self, headers: &HeaderMap) -> bool {
+        let value = match headers.get(&self.header) {
+            Some(value) => value.to_str().unwrap_or_default(),
+            None => return self.absent,
+        };
+        if self.absent {
+            return false;
+        }
+        match self.re {
+            Some(ref re) => get_regex(re).is_match(value).unwrap_or(false),
+            None => true,
+        }
+    }
+}
+
+impl CookieWhen {
+    fn is_match(&self, cookies: Option<&str>) -> bool {
+        let value = cookies.and_then(|cookies| {
+            cookies
+                .split(';')
+                .filter_map(|c| Cookie::parse(c.trim()).ok())
+                .find(|c| c.name() == self.cookie)
+                .map(|c| c.value().to_owned())
+        });
+        let value = match value {
+            Some(value) => value,
+            None => return self.absent,
+        };
+        if self.absent {
+            return false;
+        }
+        match self.re {
+            Some(ref re) => get_regex(re).is_match(&value).unwrap_or(false),
+            None => true,
+        }
+    }
+}
+
+/// A predicate on the body, checked after `when` since it has to buffer it.
+///
+/// `contains` looks for a substring, `json-path` for a node in a JSON body,
+/// which also has to equal `equals` when that is set. All that are set must
+/// match. Bodies larger than `limit` bytes never match and are passed on
+/// without being buffered whole.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BodyWhen {
+    #[serde(default)]
+    pub contains: Option<String>,
+    #[serde(default)]
+    pub json_path: Option<JsonPath>,
+    #[serde(default)]
+    pub equals: Option<Value>,
+    #[serde(default = "default_body_limit")]
+    pub limit: usize,
+}
+
+fn default_body_limit() -> usize {
+    1024 * 1024
+}
+
+impl BodyWhen {
+    /// Returns the body to pass on and whether it matched.
+    pub async fn check(&self, headers: &HeaderMap, body: Body) -> hyper::Result<(Body, bool)> {
+        let (body, fits) = within_limit(headers, body, self.limit).await?;
+        if !fits {
+            return Ok((body, false));
+        }
+        let content = to_bytes(body).await?;
+        let matched = self.is_match(&content);
+        Ok((Body::from(content), matched))
+    }
+
+    fn is_match(&self, content: &[u8]) -> bool {
+        if let Some(ref contains) = self.contains {
+            let found = !contains.is_empty()
+                && content
+                    .windows(contains.len())
+                    .any(|w| w == contains.as_bytes());
+            if !found {
+                return false;
+            }
+        }
+        if let Some(ref path) = self.json_path {
+            let value: Value = match serde_json::from_slice(content) {
+                Ok(value) => value,
+                Err(_) => return false,
+            };
+            let nodes = path.select(&value);
+            let found = match self.equals {
+                Some(ref equals) => nodes.contains(&equals),
+                None => !nodes.is_empty(),
+            };
+            if !found {
+                return false;
+            }
+        }
+        true
+    }
+}
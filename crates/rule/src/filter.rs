@@ -1,7 +1,7 @@
 use hyper::{Body, Request};
 use serde::{Deserialize, Serialize};
 
-use crate::cache::get_regex;
+use crate::{action::accepts, cache::get_regex};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
@@ -12,6 +12,8 @@ pub enum Filter {
     DomainPrefix(String),
     DomainSuffix(String),
     UrlRegex(String),
+    /// Matches when the request `Accept` header accepts this media type.
+    Accept(String),
 }
 
 impl Filter {
@@ -23,6 +25,7 @@ impl Filter {
             Filter::DomainPrefix(d) => Self::DomainPrefix(d.to_lowercase()),
             Filter::DomainSuffix(d) => Self::DomainSuffix(d.to_lowercase()),
             Filter::UrlRegex(re) => Self::UrlRegex(re.to_owned()),
+            Filter::Accept(media) => Self::Accept(media.to_lowercase()),
         }
     }
 
@@ -38,6 +41,7 @@ impl Filter {
                 let url = req.uri().to_string();
                 get_regex(target).is_match(&url).unwrap()
             }
+            Self::Accept(target) => accepts(req.headers(), target),
         }
     }
 
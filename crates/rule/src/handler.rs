@@ -1,4 +1,4 @@
-use crate::Rule;
+use crate::{Action, Filter, Rule};
 use async_trait::async_trait;
 use hyper::{header, Body, Request, Response};
 use log::info;
@@ -11,6 +11,27 @@ use std::sync::Arc;
 #[derive(Clone)]
 pub struct RuleHttpHandler {
     rules: Arc<Vec<Rule>>,
+    pipeline: Arc<Pipeline>,
+}
+
+/// Actions applied to the requests and responses the proxy handles,
+/// regardless of the matched rules.
+///
+/// `pre` runs before the matched rules and `post` after them, on the request
+/// as well as on the response. An action answering the request itself, such
+/// as `reject` or `respond`, skips everything after it, `post` included, and
+/// its response is not modified either.
+#[derive(Debug, Default, Clone)]
+pub struct Pipeline {
+    pub pre: Vec<Action>,
+    pub post: Vec<Action>,
+}
+
+impl Pipeline {
+    pub fn append(&mut self, other: &mut Pipeline) {
+        self.pre.append(&mut other.pre);
+        self.post.append(&mut other.post);
+    }
 }
 
 #[derive(Default, Clone)]
@@ -22,18 +43,31 @@ impl CustomContextData for RuleHandlerCtx {}
 
 impl RuleHttpHandler {
     pub fn new(rules: Arc<Vec<Rule>>) -> Self {
-        Self { rules }
+        Self {
+            rules,
+            pipeline: Default::default(),
+        }
+    }
+
+    pub fn with_pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.pipeline = Arc::new(pipeline);
+        self
     }
 
     fn match_rules(&self, req: &Request<Body>) -> Vec<Rule> {
         let mut matched = vec![];
+        if !self.pipeline.pre.is_empty() {
+            matched.push(Rule::new(vec![Filter::All], self.pipeline.pre.clone()));
+        }
+        // a rule matches once however many of its filters do
         for rule in self.rules.iter() {
-            for filter in &rule.filters {
-                if filter.is_match_req(req) {
-                    matched.push(rule.clone());
-                }
+            if rule.filters.iter().any(|f| f.is_match_req(req)) && rule.take_match() {
+                matched.push(rule.clone());
             }
         }
+        if !self.pipeline.post.is_empty() {
+            matched.push(Rule::new(vec![Filter::All], self.pipeline.post.clone()));
+        }
         matched
     }
 }
@@ -57,8 +91,9 @@ impl HttpHandler<RuleHandlerCtx> for RuleHttpHandler {
         }
 
         for mut rule in rules {
-            ctx.custom_data.rules.push(rule.clone());
             let rt = rule.do_req(req).await;
+            // keep the per-request state (url, forwarded_at) for `do_res`
+            ctx.custom_data.rules.push(rule);
             if let RequestOrResponse::Request(r) = rt {
                 req = r;
             } else {
@@ -96,3 +131,24 @@ impl HttpHandler<RuleHandlerCtx> for RuleHttpHandler {
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_once_per_rule() {
+        let filters = vec![Filter::All, Filter::Domain("example.com".to_owned())];
+        let rule = Rule::new(filters, vec![Action::LogReq]).only_first_n(2);
+        let handler = RuleHttpHandler::new(Arc::new(vec![rule]));
+        let req = || {
+            Request::get("http://example.com/")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert_eq!(handler.match_rules(&req()).len(), 1);
+        assert_eq!(handler.match_rules(&req()).len(), 1);
+        assert!(handler.match_rules(&req()).is_empty());
+    }
+}
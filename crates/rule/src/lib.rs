@@ -1,10 +1,17 @@
-pub use action::Action;
+pub use action::{Action, Quota, RequestHead, TextTypes};
 pub use filter::Filter;
 pub use handler::*;
 use hyper::{header, header::HeaderValue, Body, Request, Response, StatusCode};
 use log::*;
 use mitm_core::mitm::RequestOrResponse;
-use std::vec::Vec;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+    vec::Vec,
+};
 
 mod action;
 mod cache;
@@ -15,16 +22,75 @@ mod handler;
 pub struct Rule {
     pub filters: Vec<Filter>,
     pub actions: Vec<Action>,
+    /// Limits the rule to its first matches, see [`Rule::only_first_n`].
+    pub match_limit: Option<Arc<MatchLimit>>,
+    /// The bodies text modifies rewrite.
+    pub text_types: TextTypes,
 
     pub url: Option<String>,
+    pub head: Option<RequestHead>,
+    /// When this rule handed the request on towards upstream.
+    pub forwarded_at: Option<Instant>,
+    /// Subprotocols the client offered in a websocket upgrade.
+    pub ws_offered: Option<Vec<String>>,
+    /// The correlation id sent upstream, echoed on the response.
+    pub correlation_id: Option<String>,
+    /// The rate limit quota left after this request.
+    pub quota: Option<Quota>,
 }
 
 impl Rule {
+    pub fn new(filters: Vec<Filter>, actions: Vec<Action>) -> Self {
+        Self {
+            filters,
+            actions,
+            match_limit: None,
+            text_types: TextTypes::default(),
+            url: None,
+            head: None,
+            forwarded_at: None,
+            ws_offered: None,
+            correlation_id: None,
+            quota: None,
+        }
+    }
+
+    /// Lets the rule fire for its first `n` matches only, counted across all
+    /// connections for as long as the process runs. Clones of the rule share
+    /// the count.
+    pub fn only_first_n(mut self, n: u64) -> Self {
+        self.match_limit = Some(Arc::new(MatchLimit {
+            n,
+            matched: AtomicU64::new(0),
+        }));
+        self
+    }
+
+    /// Lets text modifies rewrite bodies whose `Content-Type` contains one of
+    /// `types`, instead of `text` and `javascript`.
+    pub fn text_types(mut self, types: Vec<String>) -> Self {
+        self.text_types = TextTypes::new(types);
+        self
+    }
+
+    /// Counts a match, returning whether the rule may still fire for it.
+    pub fn take_match(&self) -> bool {
+        match self.match_limit {
+            Some(ref limit) => limit.take(),
+            None => true,
+        }
+    }
+
     pub async fn do_req(&mut self, req: Request<Body>) -> RequestOrResponse {
         let url = req.uri().to_string();
         self.url = Some(url.clone());
+        self.head = Some(RequestHead::of(&req));
         let mut tmp_req = req;
 
+        // this rule is a per-request clone, so the counters' choice is kept
+        // for `do_res` as well
+        self.actions = action::expand_counters(&self.actions, &tmp_req);
+
         for action in &self.actions {
             match action {
                 Action::Reject => {
@@ -67,9 +133,19 @@ impl Rule {
                     };
                 }
 
+                Action::Respond(respond) => {
+                    info!("[Respond] {} {}", url, respond.status);
+                    return RequestOrResponse::Response(respond.build_res(&tmp_req));
+                }
+
+                Action::Maintenance(maintenance) => {
+                    info!("[Maintenance] {}", url);
+                    return RequestOrResponse::Response(maintenance.build_res(&tmp_req));
+                }
+
                 Action::ModifyRequest(modify) => {
                     info!("[ModifyRequest] {}", url);
-                    match modify.modify_req(tmp_req).await {
+                    match modify.modify_req(tmp_req, &self.text_types).await {
                         Some(new_req) => tmp_req = new_req,
                         None => {
                             return RequestOrResponse::Response(
@@ -82,11 +158,53 @@ impl Rule {
                     }
                 }
 
+                Action::WebSocketProtocol(ws) => {
+                    self.ws_offered = ws.modify_req(&mut tmp_req);
+                }
+
+                Action::CorrelationId(correlation) => {
+                    self.correlation_id = correlation.modify_req(&mut tmp_req);
+                }
+
+                Action::AuthChallenge(auth) => {
+                    if let Some(res) = auth.check(&mut tmp_req) {
+                        info!("[AuthChallenge] {} {}", url, res.status());
+                        return RequestOrResponse::Response(res);
+                    }
+                }
+
+                Action::RateLimit(rate_limit) => match rate_limit.take() {
+                    Ok(quota) => {
+                        if rate_limit.headers {
+                            self.quota = Some(quota);
+                        }
+                    }
+                    Err(limited) => {
+                        info!("[RateLimit] {}", url);
+                        return RequestOrResponse::Response(rate_limit.build_res(limited));
+                    }
+                },
+
                 Action::LogReq => {
                     info!("[LogRequest] {}", url);
                     action::log_req(&tmp_req).await;
                 }
 
+                Action::AnonymizeId(anonymize) => {
+                    info!("[AnonymizeId] {}", url);
+                    match anonymize.anonymize_req(tmp_req, &self.text_types).await {
+                        Some(new_req) => tmp_req = new_req,
+                        None => {
+                            return RequestOrResponse::Response(
+                                Response::builder()
+                                    .status(StatusCode::BAD_REQUEST)
+                                    .body(Body::default())
+                                    .unwrap(),
+                            );
+                        }
+                    }
+                }
+
                 #[cfg(feature = "js")]
                 Action::Js(ref code) => {
                     info!("[LogRequest] {}", url);
@@ -105,23 +223,55 @@ impl Rule {
             }
         }
 
+        self.forwarded_at = Some(Instant::now());
         RequestOrResponse::Request(tmp_req)
     }
 
     pub async fn do_res(&self, res: Response<Body>) -> Response<Body> {
         let url = self.url.clone().unwrap_or_default();
+        let head = self.head.clone().unwrap_or_default();
         let mut tmp_res = res;
 
         for action in &self.actions {
             match action {
                 Action::ModifyResponse(modify) => {
                     info!("[ModifyResponse] {}", url);
-                    tmp_res = modify.modify_res(tmp_res).await
+                    tmp_res = modify.modify_res(&head, tmp_res, &self.text_types).await
+                }
+                Action::OnStatus(on_status) => {
+                    info!("[OnStatus] {} {}", url, tmp_res.status());
+                    tmp_res = on_status.modify_res(&head, tmp_res, &self.text_types).await
                 }
                 Action::LogRes => {
                     info!("[LogResponse] {}", url);
                     action::log_res(&tmp_res).await;
                 }
+                Action::AnonymizeId(anonymize) => {
+                    info!("[AnonymizeId] {}", url);
+                    tmp_res = anonymize.anonymize_res(tmp_res, &self.text_types).await
+                }
+                Action::WebSocketProtocol(ws) => {
+                    if let Some(ref offered) = self.ws_offered {
+                        ws.modify_res(offered, &mut tmp_res);
+                    }
+                }
+                Action::CorrelationId(correlation) => {
+                    if let Some(ref id) = self.correlation_id {
+                        correlation.modify_res(id, &mut tmp_res);
+                    }
+                }
+                Action::RateLimit(_) => {
+                    if let Some(ref quota) = self.quota {
+                        quota.set_headers(tmp_res.headers_mut());
+                    }
+                }
+                Action::BodyBudget(budget) => {
+                    tmp_res = budget.check(&url, tmp_res).await;
+                }
+                Action::LatencyFloor(latency) => {
+                    info!("[LatencyFloor] {}", url);
+                    tmp_res = latency.apply(self.forwarded_at, tmp_res).await
+                }
 
                 #[cfg(feature = "js")]
                 Action::Js(ref code) => {
@@ -142,3 +292,20 @@ impl Rule {
         tmp_res
     }
 }
+
+#[derive(Debug)]
+pub struct MatchLimit {
+    n: u64,
+    matched: AtomicU64,
+}
+
+impl MatchLimit {
+    fn take(&self) -> bool {
+        // never goes past `n`, so the count can't wrap however long it runs
+        self.matched
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |matched| {
+                (matched < self.n).then_some(matched + 1)
+            })
+            .is_ok()
+    }
+}
@@ -11,10 +11,16 @@ pub struct Rule {
     pub filters: SingleOrMulti<rule::Filter>,
     #[serde(alias = "action")]
     pub actions: SingleOrMulti<rule::Action>,
+    #[serde(default, alias = "only-first-n")]
+    pub only_first_n: Option<u64>,
+    #[serde(default, alias = "text-types")]
+    pub text_types: Option<Vec<String>>,
 }
 
 impl From<Rule> for (rule::Rule, Vec<String>) {
     fn from(rule: Rule) -> Self {
+        let only_first_n = rule.only_first_n;
+        let text_types = rule.text_types;
         let filters: Vec<rule::Filter> = rule
             .filters
             .into_vec()
@@ -33,11 +39,13 @@ impl From<Rule> for (rule::Rule, Vec<String>) {
         };
         mitm_filters.append(&mut mitm_list_2);
 
-        let rule = rule::Rule {
-            filters,
-            actions: rule.actions.into_vec(),
-            url: None,
-        };
+        let mut rule = rule::Rule::new(filters, rule.actions.into_vec());
+        if let Some(n) = only_first_n {
+            rule = rule.only_first_n(n);
+        }
+        if let Some(types) = text_types {
+            rule = rule.text_types(types);
+        }
 
         (rule, mitm_filters)
     }
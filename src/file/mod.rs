@@ -1,14 +1,75 @@
 use anyhow::Result;
 use log::error;
+use serde::{
+    de::{
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+        MapAccess, SeqAccess, Visitor,
+    },
+    Deserialize, Deserializer, Serialize,
+};
+use serde_yaml::with::singleton_map_recursive;
 use single_multi::SingleOrMulti;
-use std::{fs, io::BufReader, path::Path};
+use std::{fmt, fs, io::BufReader, path::Path};
 
 pub mod frule;
 mod single_multi;
 
+/// A rule file is either a plain list of rules, or a map that may also hold
+/// the `pre` and `post` pipelines.
+///
+/// Which one is told by whether the file is a sequence or a map, so the
+/// error of the rule that failed to load reaches the user and rules are
+/// only loaded once.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+enum RuleFile {
+    Rules(Vec<frule::Rule>),
+    Config {
+        rules: Vec<frule::Rule>,
+        pre: Vec<rule::Action>,
+        post: Vec<rule::Action>,
+    },
+}
+
+#[derive(Deserialize)]
+struct Config {
+    #[serde(default)]
+    rules: Vec<frule::Rule>,
+    #[serde(default, deserialize_with = "singleton_map_recursive::deserialize")]
+    pre: Vec<rule::Action>,
+    #[serde(default, deserialize_with = "singleton_map_recursive::deserialize")]
+    post: Vec<rule::Action>,
+}
+
+impl<'de> Deserialize<'de> for RuleFile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RuleFileVisitor;
+
+        impl<'de> Visitor<'de> for RuleFileVisitor {
+            type Value = RuleFile;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a list of rules or a map with rules, pre and post")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<RuleFile, A::Error> {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(RuleFile::Rules)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<RuleFile, A::Error> {
+                let Config { rules, pre, post } =
+                    Config::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(RuleFile::Config { rules, pre, post })
+            }
+        }
+
+        deserializer.deserialize_any(RuleFileVisitor)
+    }
+}
+
 pub fn load_rules_amd_mitm_filters<P: AsRef<Path> + Clone>(
     path: P,
-) -> Result<(Vec<rule::Rule>, Vec<String>)> {
+) -> Result<(Vec<rule::Rule>, Vec<String>, rule::Pipeline)> {
     let m = fs::metadata(&path).expect("Not a valid path");
     if m.file_type().is_dir() {
         load_rules_amd_mitm_filters_from_dir(path)
@@ -19,11 +80,12 @@ pub fn load_rules_amd_mitm_filters<P: AsRef<Path> + Clone>(
 
 fn load_rules_amd_mitm_filters_from_file<P: AsRef<Path> + Clone>(
     path: P,
-) -> Result<(Vec<rule::Rule>, Vec<String>)> {
+) -> Result<(Vec<rule::Rule>, Vec<String>, rule::Pipeline)> {
     let file = fs::File::open(path.clone())?;
     let reader = BufReader::new(file);
-    let rules: Vec<frule::Rule> = match serde_yaml::from_reader(reader) {
-        Ok(rules) => rules,
+    let (rules, pipeline) = match serde_yaml::from_reader(reader) {
+        Ok(RuleFile::Rules(rules)) => (rules, rule::Pipeline::default()),
+        Ok(RuleFile::Config { rules, pre, post }) => (rules, rule::Pipeline { pre, post }),
         Err(err) => {
             error!(
                 "load rule ({}) failed: {err}",
@@ -42,28 +104,56 @@ fn load_rules_amd_mitm_filters_from_file<P: AsRef<Path> + Clone>(
             (a, b)
         });
 
-    Ok((rules, filters))
+    Ok((rules, filters, pipeline))
 }
 
 fn load_rules_amd_mitm_filters_from_dir<P: AsRef<Path>>(
     path: P,
-) -> Result<(Vec<rule::Rule>, Vec<String>)> {
+) -> Result<(Vec<rule::Rule>, Vec<String>, rule::Pipeline)> {
     let dir = fs::read_dir(path).expect("Not a valid dir");
 
-    let (rules, filters) = dir
+    let (rules, filters, pipeline) = dir
         .flatten()
         .filter(|f| f.file_type().is_ok())
         .filter(|f| f.file_type().ok().unwrap().is_file())
         .map(|f| load_rules_amd_mitm_filters_from_file(f.path()))
         .filter_map(|r| r.ok())
         .fold(
-            (vec![], vec![]),
-            |(mut a, mut b), (mut rule, mut filters)| {
+            (vec![], vec![], rule::Pipeline::default()),
+            |(mut a, mut b, mut c), (mut rule, mut filters, mut pipeline)| {
                 a.append(&mut rule);
                 b.append(&mut filters);
-                (a, b)
+                c.append(&mut pipeline);
+                (a, b, c)
             },
         );
 
-    Ok((rules, filters))
+    Ok((rules, filters, pipeline))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rule_file_pipelines() {
+        let yaml = r#"
+pre:
+  - modify-request:
+      header:
+        key: x-pre
+        value: on
+post:
+  - reject
+  - modify-response:
+      status: 200
+"#;
+        match serde_yaml::from_str(yaml).unwrap() {
+            RuleFile::Config { rules, pre, post } => {
+                assert!(rules.is_empty());
+                assert_eq!((pre.len(), post.len()), (1, 2));
+            }
+            RuleFile::Rules(_) => panic!("not a config"),
+        }
+    }
 }
@@ -1,7 +1,20 @@
-use serde::{Deserialize, Serialize};
-use std::vec::Vec;
+use serde::{
+    de::{
+        self,
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+        MapAccess, SeqAccess, Visitor,
+    },
+    Deserialize, Deserializer, Serialize,
+};
+use serde_yaml::with::singleton_map_recursive;
+use std::{fmt, marker::PhantomData, vec::Vec};
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Either a single value or a list of them.
+///
+/// Which one is told by whether the value is a sequence, so the error of a
+/// value that failed to load reaches the user as it is. Enums within are
+/// read from single key maps, as in `- modify-response: ...`.
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum SingleOrMulti<T> {
     Single(T),
@@ -22,3 +35,67 @@ impl<T> From<SingleOrMulti<T>> for Vec<T> {
         }
     }
 }
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for SingleOrMulti<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SingleOrMultiVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for SingleOrMultiVisitor<T> {
+            type Value = SingleOrMulti<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a value or a list of values")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                singleton_map_recursive::deserialize(SeqAccessDeserializer::new(seq))
+                    .map(SingleOrMulti::Multi)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                singleton_map_recursive::deserialize(MapAccessDeserializer::new(map))
+                    .map(SingleOrMulti::Single)
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                T::deserialize(de::value::StrDeserializer::new(v)).map(SingleOrMulti::Single)
+            }
+
+            fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+                T::deserialize(de::value::StringDeserializer::new(v)).map(SingleOrMulti::Single)
+            }
+        }
+
+        deserializer.deserialize_any(SingleOrMultiVisitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_or_multi() {
+        let single: SingleOrMulti<rule::Action> = serde_yaml::from_str("reject").unwrap();
+        assert_eq!(single.into_vec().len(), 1);
+
+        let multi: SingleOrMulti<rule::Action> =
+            serde_yaml::from_str("- reject\n- redirect: https://example.com\n").unwrap();
+        assert_eq!(multi.into_vec().len(), 2);
+
+        let names: SingleOrMulti<String> = serde_yaml::from_str("example.com").unwrap();
+        assert_eq!(names.into_vec(), ["example.com"]);
+    }
+
+    #[test]
+    fn replacement_error() {
+        let action = "modify-response:\n  body:\n    re: '(\\d+)'\n    new: '$2'\n";
+        for yaml in [
+            action.to_owned(),
+            format!("- {}", action.replace('\n', "\n  ")),
+        ] {
+            let err = serde_yaml::from_str::<SingleOrMulti<rule::Action>>(&yaml).unwrap_err();
+            assert!(err.to_string().contains("refers to group $2"), "{}", err);
+        }
+    }
+}
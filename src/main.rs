@@ -43,6 +43,8 @@ struct Run {
     bind: String,
     #[clap(short, long, help = "upstream proxy")]
     proxy: Option<String>,
+    #[clap(long, help = "ask TLS clients for a certificate")]
+    client_cert: bool,
 }
 
 #[derive(Parser)]
@@ -94,9 +96,9 @@ async fn run(opts: &Run) -> Result<()> {
 
     info!("Http Proxy listen on: http://{}", opts.bind);
 
-    let (rules, mitm_filters) = file::load_rules_amd_mitm_filters(&opts.rule)?;
+    let (rules, mitm_filters, pipeline) = file::load_rules_amd_mitm_filters(&opts.rule)?;
     let rules = Arc::new(rules);
-    let http_handler = RuleHttpHandler::new(rules);
+    let http_handler = RuleHttpHandler::new(rules).with_pipeline(pipeline);
 
     let proxy = Proxy::builder()
         .ca(ca.clone())
@@ -109,6 +111,7 @@ async fn run(opts: &Run) -> Result<()> {
         .shutdown_signal(shutdown_signal())
         .mitm_filters(mitm_filters.clone())
         .handler(http_handler.clone())
+        .request_client_cert(opts.client_cert)
         .build();
 
     tokio::spawn(proxy.start_proxy());